@@ -1 +1 @@
-export default ['jpeg', 'jpg', 'png'];
+export default ['jpeg', 'jpg', 'png', 'gif', 'tif', 'tiff', 'bmp', 'tga', 'webp', 'avif', 'svg'];
@@ -12,7 +12,10 @@ export const useOptimizationStore = defineStore('optimization', () => {
   const optimization = ref(['compress']);
   const path = ref(['same']);
   const saveMethod = ref('rename');
+  const backup = ref('keep');
   const savePath = ref('');
+  const incremental = ref(false);
+  const convertTo = ref('png');
 
   const isProcessing = ref(false);
   const progress = ref({
@@ -23,8 +26,16 @@ export const useOptimizationStore = defineStore('optimization', () => {
   });
   const result = ref(null);
   const error = ref(null);
+  const fileResults = ref([]);
+  const toolHealth = ref([]);
 
   async function initListeners() {
+    try {
+      toolHealth.value = await invoke('check_tools');
+    } catch (e) {
+      console.error('Tool check failed:', e);
+    }
+
     const processingState = await invoke('get_processing_state');
     isProcessing.value = processingState;
 
@@ -42,6 +53,7 @@ export const useOptimizationStore = defineStore('optimization', () => {
       if (event.payload === true) {
         result.value = null;
         error.value = null;
+        fileResults.value = [];
         progress.value = {
           total: 0,
           done: 0,
@@ -67,6 +79,10 @@ export const useOptimizationStore = defineStore('optimization', () => {
         percentage: total > 0 ? Math.round((done / total) * 100) : 0,
       };
     });
+
+    await listen('file_done', (event) => {
+      fileResults.value.push(event.payload);
+    });
   }
 
   async function fetchLastResult() {
@@ -88,32 +104,41 @@ export const useOptimizationStore = defineStore('optimization', () => {
     }
   }
 
+  async function buildConfig() {
+    let outputDir = null;
+    if (path.value.includes('downloads')) {
+      outputDir = await downloadDir();
+    } else if (path.value.includes('custom')) {
+      outputDir = savePath.value;
+    }
+
+    const replace =
+      path.value.includes('same') && saveMethod.value === 'overwrite';
+
+    return {
+      tasks: fileTasks.value,
+      jpg_q: Math.max(10, parseInt(quality.value)),
+      png_max: Math.max(10, parseInt(quality.value)),
+      png_min: Math.max(10, parseInt(quality.value) - 15),
+      webp: optimization.value.includes('webp'),
+      avif: optimization.value.includes('avif'),
+      jxl: optimization.value.includes('jxl'),
+      optimize_original: optimization.value.includes('compress'),
+      replace,
+      backup: replace && backup.value === 'keep',
+      incremental: incremental.value,
+      output_dir: outputDir,
+      convert_to: convertTo.value,
+    };
+  }
+
   async function startOptimization() {
     resetState();
 
     isProcessing.value = true;
 
     try {
-      let outputDir = null;
-      if (path.value.includes('downloads')) {
-        outputDir = await downloadDir();
-      } else if (path.value.includes('custom')) {
-        outputDir = savePath.value;
-      }
-
-      const config = {
-        tasks: fileTasks.value,
-        jpg_q: Math.max(10, parseInt(quality.value)),
-        png_max: Math.max(10, parseInt(quality.value)),
-        png_min: Math.max(10, parseInt(quality.value) - 15),
-        webp: optimization.value.includes('webp'),
-        avif: optimization.value.includes('avif'),
-        optimize_original: optimization.value.includes('compress'),
-        replace:
-          path.value.includes('same') && saveMethod.value === 'overwrite',
-        output_dir: outputDir,
-      };
-
+      const config = await buildConfig();
       const res = await invoke('run_optimization', { config });
       result.value = res;
       filesStore.clearAll();
@@ -126,6 +151,26 @@ export const useOptimizationStore = defineStore('optimization', () => {
     }
   }
 
+  async function estimateOptimization(sample = null) {
+    resetState();
+
+    isProcessing.value = true;
+
+    try {
+      const config = {
+        ...(await buildConfig()),
+        dry_run: true,
+        dry_run_sample: sample,
+      };
+      result.value = await invoke('run_optimization', { config });
+    } catch (err) {
+      console.error('Estimate failed:', err);
+      error.value = err;
+    } finally {
+      isProcessing.value = false;
+    }
+  }
+
   async function cancelOptimization() {
     try {
       await invoke('cancel_optimization');
@@ -134,11 +179,22 @@ export const useOptimizationStore = defineStore('optimization', () => {
     }
   }
 
+  async function undoLastRun() {
+    try {
+      return await invoke('undo_last_run');
+    } catch (e) {
+      console.error('Undo failed:', e);
+      error.value = e;
+      return null;
+    }
+  }
+
   function resetState() {
     localStorage.setItem('has_unviewed_result', 'false');
 
     result.value = null;
     error.value = null;
+    fileResults.value = [];
     progress.value = {
       total: 0,
       done: 0,
@@ -152,14 +208,21 @@ export const useOptimizationStore = defineStore('optimization', () => {
     optimization,
     path,
     saveMethod,
+    backup,
     savePath,
+    incremental,
+    convertTo,
     isProcessing,
     progress,
     result,
     error,
+    fileResults,
+    toolHealth,
     initListeners,
     startOptimization,
+    estimateOptimization,
     cancelOptimization,
+    undoLastRun,
     resetState,
   };
 });
@@ -1,3 +1,5 @@
 fn main() {
-    tauri_build::build()
+    if std::env::var_os("CARGO_FEATURE_GUI").is_some() {
+        tauri_build::build()
+    }
 }
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::image_ops::write_atomic;
+use crate::manifest::forget;
+use crate::types::{RestoreFailure, RestoreReport};
+
+const MANIFEST: &str = "manifest.jsonl";
+
+#[derive(Serialize, Deserialize)]
+struct BackupEntry {
+    original: String,
+    file: String,
+    size: u64,
+    hash: String,
+}
+
+// One directory per run. Each saved original is fsynced before its manifest
+// line is appended, so the manifest never points at a partial copy.
+pub struct BackupStore {
+    dir: PathBuf,
+    id: String,
+    manifest: Mutex<File>,
+    counter: AtomicU64,
+}
+
+impl BackupStore {
+    pub fn create(root: &Path) -> Result<Self, String> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+
+        let mut id = millis.to_string();
+        let mut suffix = 1;
+        while root.join(&id).exists() {
+            id = format!("{}-{}", millis, suffix);
+            suffix += 1;
+        }
+
+        let dir = root.join(&id);
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create backup folder {:?}: {}", dir, e))?;
+        let manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(MANIFEST))
+            .map_err(|e| format!("Failed to create backup manifest: {}", e))?;
+
+        Ok(BackupStore {
+            dir,
+            id,
+            manifest: Mutex::new(manifest),
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|e| format!("Failed to read original: {}", e))?;
+
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let file = match path.extension() {
+            Some(ext) => format!("{}.{}", n, ext.to_string_lossy()),
+            None => n.to_string(),
+        };
+        write_atomic(&self.dir.join(&file), &data)?;
+
+        let entry = BackupEntry {
+            original: path.to_string_lossy().to_string(),
+            file,
+            size: data.len() as u64,
+            hash: blake3::hash(&data).to_hex().to_string(),
+        };
+        let line = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+
+        let mut manifest = self.manifest.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(manifest, "{}", line)
+            .and_then(|_| manifest.sync_data())
+            .map_err(|e| format!("Failed to write backup manifest: {}", e))
+    }
+}
+
+// Run ids with whether anything was saved in them, oldest first
+fn run_dirs(root: &Path) -> Result<Vec<(String, bool)>, String> {
+    let entries =
+        fs::read_dir(root).map_err(|e| format!("Failed to read backups {:?}: {}", root, e))?;
+
+    let mut runs: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let saved = fs::metadata(e.path().join(MANIFEST)).ok()?.len() > 0;
+            let name = e.file_name().to_string_lossy().to_string();
+            let (millis, suffix) = name.split_once('-').unwrap_or((name.as_str(), "0"));
+            let key = (millis.parse::<u128>().ok()?, suffix.parse::<u32>().ok()?);
+            Some((key, name, saved))
+        })
+        .collect();
+    runs.sort();
+    Ok(runs
+        .into_iter()
+        .map(|(_, name, saved)| (name, saved))
+        .collect())
+}
+
+// Runs that saved no original have nothing to restore, so they are left out
+fn list_runs(root: &Path) -> Result<Vec<String>, String> {
+    Ok(run_dirs(root)?
+        .into_iter()
+        .filter(|(_, saved)| *saved)
+        .map(|(name, _)| name)
+        .collect())
+}
+
+pub fn latest_run(root: &Path) -> Result<String, String> {
+    list_runs(root)?
+        .pop()
+        .ok_or_else(|| "No backups found.".to_string())
+}
+
+// Deletes empty runs and all but the newest `keep` others
+pub fn prune_runs(root: &Path, keep: usize) -> Result<(), String> {
+    let (runs, empty): (Vec<_>, Vec<_>) =
+        run_dirs(root)?.into_iter().partition(|(_, saved)| *saved);
+    let old = &runs[..runs.len().saturating_sub(keep)];
+    for (run, _) in old.iter().chain(&empty) {
+        fs::remove_dir_all(root.join(run))
+            .map_err(|e| format!("Failed to remove backup {}: {}", run, e))?;
+    }
+    Ok(())
+}
+
+pub fn undo_last_run(root: &Path, manifest_path: Option<&Path>) -> Result<RestoreReport, String> {
+    restore_files(root, &latest_run(root)?, None, manifest_path)
+}
+
+// Restores every original of a run, or only the given paths. Each copy is
+// checked against its recorded hash before it replaces the current file, and
+// restored files are dropped from the incremental manifest, if any.
+pub fn restore_files(
+    root: &Path,
+    run_id: &str,
+    only: Option<&[String]>,
+    manifest_path: Option<&Path>,
+) -> Result<RestoreReport, String> {
+    let dir = root.join(run_id);
+    let manifest = File::open(dir.join(MANIFEST))
+        .map_err(|e| format!("Failed to open backup {}: {}", run_id, e))?;
+
+    let mut report = RestoreReport {
+        run_id: run_id.to_string(),
+        restored: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    let mut found = Vec::new();
+    let mut restored_hashes = Vec::new();
+    for line in BufReader::new(manifest).lines() {
+        let line = line.map_err(|e| format!("Failed to read backup manifest: {}", e))?;
+        // A crash can leave a torn last line; everything before it is intact
+        let Ok(entry) = serde_json::from_str::<BackupEntry>(&line) else {
+            continue;
+        };
+        if only.is_some_and(|paths| !paths.contains(&entry.original)) {
+            continue;
+        }
+
+        found.push(entry.original.clone());
+        match restore_entry(&dir, &entry) {
+            Ok(()) => {
+                restored_hashes.push(entry.hash);
+                report.restored.push(entry.original);
+            }
+            Err(reason) => report.failed.push(RestoreFailure {
+                path: entry.original,
+                reason,
+            }),
+        }
+    }
+
+    for path in only.unwrap_or_default() {
+        if !found.contains(path) {
+            report.failed.push(RestoreFailure {
+                path: path.clone(),
+                reason: "Not in backup".to_string(),
+            });
+        }
+    }
+
+    if let Some(path) = manifest_path.filter(|_| !restored_hashes.is_empty()) {
+        if let Err(reason) = forget(path, &restored_hashes) {
+            report.failed.push(RestoreFailure {
+                path: path.to_string_lossy().to_string(),
+                reason,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+fn restore_entry(dir: &Path, entry: &BackupEntry) -> Result<(), String> {
+    let data = fs::read(dir.join(&entry.file))
+        .map_err(|e| format!("Failed to read backup copy: {}", e))?;
+
+    if data.len() as u64 != entry.size || blake3::hash(&data).to_hex().as_str() != entry.hash {
+        return Err("Backup copy is corrupted".to_string());
+    }
+
+    let original = Path::new(&entry.original);
+    if let Some(parent) = original.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create folder: {}", e))?;
+    }
+    write_atomic(original, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_run(root: &Path, original: &Path) -> String {
+        let store = BackupStore::create(root).unwrap();
+        store.save(original).unwrap();
+        store.id().to_string()
+    }
+
+    #[test]
+    fn prune_runs_keeps_the_newest_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("backups");
+        let original = dir.path().join("photo.jpg");
+        fs::write(&original, b"original").unwrap();
+        let ids: Vec<String> = (0..7).map(|_| saved_run(&root, &original)).collect();
+
+        prune_runs(&root, 5).unwrap();
+
+        assert_eq!(list_runs(&root).unwrap(), ids[2..]);
+        assert_eq!(latest_run(&root).unwrap(), ids[6]);
+    }
+
+    #[test]
+    fn empty_runs_are_not_restored_and_get_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("backups");
+        let original = dir.path().join("photo.jpg");
+        fs::write(&original, b"original").unwrap();
+        let saved = saved_run(&root, &original);
+        let empty = BackupStore::create(&root).unwrap().id().to_string();
+
+        assert_eq!(latest_run(&root).unwrap(), saved);
+
+        prune_runs(&root, 5).unwrap();
+        assert!(root.join(&saved).exists());
+        assert!(!root.join(&empty).exists());
+    }
+}
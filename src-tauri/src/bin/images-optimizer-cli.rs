@@ -0,0 +1,618 @@
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use app_lib::backup::undo_last_run;
+use app_lib::optimizer::perform_optimization;
+use app_lib::progress::ProgressSink;
+use app_lib::tools;
+use app_lib::types::{
+    AvifBitDepth, AvifSettings, ConvertTarget, FileOutcome, FileStats, FileTask, FitMode, JxlMode,
+    JxlSettings, MetadataPolicy, OptimizeConfig, PngBackend, PngInterlace, PngPreset, PngSettings,
+    ProgressPayload, ResizeConfig, ResizeFilter, SvgSettings, WebpMode, WebpSettings,
+};
+use humansize::{format_size, DECIMAL};
+
+const USAGE: &str = "Usage: images-optimizer-cli [OPTIONS] <PATH>...
+
+Options:
+  -q, --quality <N>     JPEG quality and PNG max quality, 10-100 (default: 80)
+      --png-min <N>     PNG min quality (default: quality - 15)
+      --png-backend <B> native or external pngquant/oxipng (default: native)
+      --png-preset <P>  fast, balanced or maximum (default: balanced)
+      --oxipng-level <N>
+                        oxipng optimization level, 0-6
+      --zopfli          Use zopfli deflate (slow, smallest output)
+      --interlace <I>   off, adam7 or keep (default: off)
+      --png-speed <N>   Quantization speed, 1-10
+      --dithering <F>   Dithering level, 0-1 (0 disables)
+      --posterize <N>   Reduce precision by N bits, 0-4
+      --tool-timeout <S>
+                        Per-file limit for PNG tools in seconds, 0 disables (default: 300)
+      --webp            Also generate a .webp next to every output
+      --webp-quality <N>
+                        WebP quality, 0-100 (default: 75)
+      --webp-mode <M>   lossy, lossless, near-lossless or auto (default: lossy);
+                        auto picks lossless for PNG graphics
+      --webp-near-lossless <N>
+                        Near-lossless preprocessing, 0-100, lower is stronger (default: 60)
+      --webp-method <N> WebP effort, 0-6 (default: 4)
+      --webp-alpha-quality <N>
+                        WebP alpha quality, 0-100 (default: 100)
+      --avif            Also generate an .avif next to every output
+      --avif-quality <N>
+                        AVIF quality, 1-100 (default: 65)
+      --avif-speed <N>  AVIF speed, 1-10 (default: 4)
+      --avif-alpha-quality <N>
+                        AVIF alpha quality, 1-100 (default: 70)
+      --avif-depth <D>  8, 10 or auto (default: auto)
+      --jxl             Also generate a .jxl next to every output (needs cjxl from libjxl)
+      --jxl-quality <N> JPEG XL quality, 1-100 (default: 85)
+      --jxl-mode <M>    lossy or lossless (default: lossy)
+      --jxl-effort <N>  JPEG XL effort, 1-9 (default: 7)
+      --jxl-reencode-jpeg
+                        Encode JPEG sources from pixels instead of recompressing them
+                        losslessly
+      --svg-precision <N>
+                        Decimal places kept in SVG coordinates, 0-8 (default: 3)
+      --no-compress     Do not optimize the original format
+      --replace         Overwrite originals instead of writing *__optimized files
+      --backup <DIR>    Keep a copy of every overwritten original under DIR
+      --backup-keep <N> Backup runs kept under DIR, older ones are deleted (default: 5)
+      --undo <DIR>      Restore the originals of the last run backed up under DIR and exit;
+                        with --incremental, the restored files are processed again next run
+      --metadata <MODE> strip, icc, copyright or all (default: strip)
+      --max-width <PX>  Downscale images wider than PX
+      --max-height <PX> Downscale images taller than PX
+      --fit <MODE>      contain, cover or fill when resizing (default: contain)
+      --widths <LIST>   Comma separated responsive widths, e.g. 640,1280
+      --target-ssim <S> Pick the lowest JPEG/WebP quality reaching SSIM S, e.g. 0.98
+      --max-bytes <N>   Fail files whose outputs cannot be brought under N bytes
+                        (not available with --jxl)
+      --downscale-to-fit
+                        Allow shrinking dimensions to meet --max-bytes
+  -o, --output <DIR>    Write results into DIR, keeping the folder structure
+      --convert-to <F>  Format for TIFF, BMP and TGA inputs: jpeg, png, webp or avif
+                        (default: png)
+      --dry-run         Encode in memory only and print the projected savings
+      --sample <N>      With --dry-run, encode only N evenly spread files
+      --incremental <FILE>
+                        Skip files already processed with the same settings, tracked in FILE
+      --check-tools     Report the external PNG, AVIF and JPEG XL tools and exit
+  -h, --help            Print this help";
+
+fn parse_args(args: Vec<String>) -> Result<OptimizeConfig, String> {
+    let mut quality: u8 = 80;
+    let mut png_min: Option<u8> = None;
+    let mut png_backend = PngBackend::Native;
+    let mut tool_timeout_secs = 300;
+    let mut png_preset = PngPreset::Balanced;
+    let mut oxipng_level = None;
+    let mut zopfli = None;
+    let mut interlace = None;
+    let mut png_speed = None;
+    let mut dithering = None;
+    let mut posterize = None;
+    let mut webp = false;
+    let mut avif = false;
+    let mut optimize_original = true;
+    let mut replace = false;
+    let mut backup_dir = None;
+    let mut backup_keep_runs = 5;
+    let mut output_dir = None;
+    let mut metadata = MetadataPolicy::StripAll;
+    let mut convert_to = ConvertTarget::Png;
+    let mut max_width = None;
+    let mut max_height = None;
+    let mut fit = FitMode::Contain;
+    let mut responsive_widths = Vec::new();
+    let mut target_ssim = None;
+    let mut max_bytes = None;
+    let mut downscale_to_fit = false;
+    let mut webp_settings = WebpSettings::default();
+    let mut avif_settings = AvifSettings::default();
+    let mut jxl = false;
+    let mut jxl_settings = JxlSettings::default();
+    let mut svg_settings = SvgSettings::default();
+    let mut dry_run = false;
+    let mut dry_run_sample = None;
+    let mut manifest_path = None;
+    let mut tasks = Vec::new();
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-q" | "--quality" => quality = parse_quality(&arg, iter.next())?,
+            "--png-min" => png_min = Some(parse_quality(&arg, iter.next())?),
+            "--png-backend" => {
+                png_backend = match iter.next().as_deref() {
+                    Some("native") => PngBackend::Native,
+                    Some("external") => PngBackend::External,
+                    _ => return Err("--png-backend must be native or external".into()),
+                };
+            }
+            "--png-preset" => {
+                png_preset = match iter.next().as_deref() {
+                    Some("fast") => PngPreset::Fast,
+                    Some("balanced") => PngPreset::Balanced,
+                    Some("maximum") => PngPreset::Maximum,
+                    _ => return Err("--png-preset must be fast, balanced or maximum".into()),
+                };
+            }
+            "--oxipng-level" => oxipng_level = Some(parse_number(&arg, iter.next())?),
+            "--zopfli" => zopfli = Some(true),
+            "--interlace" => {
+                interlace = match iter.next().as_deref() {
+                    Some("off") => Some(PngInterlace::Off),
+                    Some("adam7") => Some(PngInterlace::Adam7),
+                    Some("keep") => Some(PngInterlace::Keep),
+                    _ => return Err("--interlace must be off, adam7 or keep".into()),
+                };
+            }
+            "--png-speed" => png_speed = Some(parse_number(&arg, iter.next())?),
+            "--dithering" => {
+                let value = iter.next().ok_or("--dithering requires a value")?;
+                dithering = Some(
+                    value
+                        .parse::<f32>()
+                        .map_err(|_| format!("--dithering expects a number, got {}", value))?,
+                );
+            }
+            "--posterize" => posterize = Some(parse_number(&arg, iter.next())?),
+            "--tool-timeout" => {
+                let value = iter.next().ok_or("--tool-timeout requires a value")?;
+                tool_timeout_secs = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("--tool-timeout expects seconds, got {}", value))?;
+            }
+            "--webp" => webp = true,
+            "--avif" => avif = true,
+            "--webp-quality" => webp_settings.quality = parse_number(&arg, iter.next())?,
+            "--webp-mode" => {
+                webp_settings.mode = match iter.next().as_deref() {
+                    Some("lossy") => WebpMode::Lossy,
+                    Some("lossless") => WebpMode::Lossless,
+                    Some("near-lossless") => WebpMode::NearLossless,
+                    Some("auto") => WebpMode::Auto,
+                    _ => {
+                        return Err(
+                            "--webp-mode must be lossy, lossless, near-lossless or auto".into()
+                        )
+                    }
+                };
+            }
+            "--webp-near-lossless" => {
+                webp_settings.near_lossless = parse_number(&arg, iter.next())?
+            }
+            "--webp-method" => webp_settings.method = parse_number(&arg, iter.next())?,
+            "--webp-alpha-quality" => {
+                webp_settings.alpha_quality = parse_number(&arg, iter.next())?
+            }
+            "--avif-quality" => avif_settings.quality = parse_number(&arg, iter.next())?,
+            "--avif-speed" => avif_settings.speed = parse_number(&arg, iter.next())?,
+            "--avif-alpha-quality" => {
+                avif_settings.alpha_quality = parse_number(&arg, iter.next())?
+            }
+            "--jxl" => jxl = true,
+            "--jxl-quality" => jxl_settings.quality = parse_number(&arg, iter.next())?,
+            "--jxl-mode" => {
+                jxl_settings.mode = match iter.next().as_deref() {
+                    Some("lossy") => JxlMode::Lossy,
+                    Some("lossless") => JxlMode::Lossless,
+                    _ => return Err("--jxl-mode must be lossy or lossless".into()),
+                };
+            }
+            "--jxl-effort" => jxl_settings.effort = parse_number(&arg, iter.next())?,
+            "--jxl-reencode-jpeg" => jxl_settings.lossless_jpeg = false,
+            "--svg-precision" => svg_settings.precision = parse_number(&arg, iter.next())?,
+            "--avif-depth" => {
+                avif_settings.bit_depth = match iter.next().as_deref() {
+                    Some("8") => AvifBitDepth::Eight,
+                    Some("10") => AvifBitDepth::Ten,
+                    Some("auto") => AvifBitDepth::Auto,
+                    _ => return Err("--avif-depth must be 8, 10 or auto".into()),
+                };
+            }
+            "--no-compress" => optimize_original = false,
+            "--replace" => replace = true,
+            "--backup" => {
+                backup_dir = Some(iter.next().ok_or("--backup requires a directory")?);
+            }
+            "--backup-keep" => backup_keep_runs = parse_number(&arg, iter.next())?.into(),
+            "-o" | "--output" => {
+                output_dir = Some(iter.next().ok_or("--output requires a directory")?);
+            }
+            "--convert-to" => {
+                convert_to = match iter.next().as_deref() {
+                    Some("jpeg") | Some("jpg") => ConvertTarget::Jpeg,
+                    Some("png") => ConvertTarget::Png,
+                    Some("webp") => ConvertTarget::Webp,
+                    Some("avif") => ConvertTarget::Avif,
+                    _ => return Err("--convert-to must be jpeg, png, webp or avif".into()),
+                };
+            }
+            "--metadata" => {
+                metadata = match iter.next().as_deref() {
+                    Some("strip") => MetadataPolicy::StripAll,
+                    Some("icc") => MetadataPolicy::KeepIcc,
+                    Some("copyright") => MetadataPolicy::KeepCopyright,
+                    Some("all") => MetadataPolicy::KeepAll,
+                    _ => return Err("--metadata must be strip, icc, copyright or all".into()),
+                };
+            }
+            "--max-width" => max_width = Some(parse_pixels(&arg, iter.next())?),
+            "--max-height" => max_height = Some(parse_pixels(&arg, iter.next())?),
+            "--fit" => {
+                fit = match iter.next().as_deref() {
+                    Some("contain") => FitMode::Contain,
+                    Some("cover") => FitMode::Cover,
+                    Some("fill") => FitMode::Fill,
+                    _ => return Err("--fit must be contain, cover or fill".into()),
+                };
+            }
+            "--widths" => {
+                let list = iter.next().ok_or("--widths requires a list")?;
+                for w in list.split(',') {
+                    responsive_widths.push(parse_pixels(&arg, Some(w.trim().to_string()))?);
+                }
+            }
+            "--target-ssim" => {
+                let value = iter.next().ok_or("--target-ssim requires a value")?;
+                let target = value
+                    .parse::<f64>()
+                    .map_err(|_| format!("--target-ssim expects a number, got {}", value))?;
+                target_ssim = Some(target);
+            }
+            "--max-bytes" => {
+                let value = iter.next().ok_or("--max-bytes requires a value")?;
+                match value.parse::<u64>() {
+                    Ok(n) if n > 0 => max_bytes = Some(n),
+                    _ => {
+                        return Err(format!(
+                            "--max-bytes expects a positive size, got {}",
+                            value
+                        ))
+                    }
+                }
+            }
+            "--downscale-to-fit" => downscale_to_fit = true,
+            "--dry-run" => dry_run = true,
+            "--incremental" => {
+                manifest_path = Some(iter.next().ok_or("--incremental requires a file")?);
+            }
+            "--sample" => {
+                let value = iter.next().ok_or("--sample requires a value")?;
+                match value.parse::<usize>() {
+                    Ok(n) if n > 0 => dry_run_sample = Some(n),
+                    _ => return Err(format!("--sample expects a positive count, got {}", value)),
+                }
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => tasks.push(FileTask {
+                path: arg.clone(),
+                root: arg,
+            }),
+        }
+    }
+
+    if tasks.is_empty() {
+        return Err("No input paths given.".to_string());
+    }
+
+    let customized = oxipng_level.is_some()
+        || zopfli.is_some()
+        || interlace.is_some()
+        || png_speed.is_some()
+        || dithering.is_some()
+        || posterize.is_some();
+    let png_settings = customized.then(|| {
+        let base = PngSettings::from(png_preset);
+        PngSettings {
+            oxipng_level: oxipng_level.unwrap_or(base.oxipng_level),
+            zopfli: zopfli.unwrap_or(base.zopfli),
+            interlace: interlace.unwrap_or(base.interlace),
+            speed: png_speed.unwrap_or(base.speed),
+            dithering: dithering.unwrap_or(base.dithering),
+            posterize: posterize.unwrap_or(base.posterize),
+        }
+    });
+
+    Ok(OptimizeConfig {
+        tasks,
+        jpg_q: quality,
+        png_min: png_min.unwrap_or(quality.saturating_sub(15).max(10)),
+        png_max: quality,
+        png_backend,
+        tool_timeout_secs,
+        png_preset,
+        png_settings,
+        webp,
+        avif,
+        jxl,
+        optimize_original,
+        replace,
+        backup: backup_dir.is_some(),
+        backup_dir,
+        backup_keep_runs,
+        output_dir,
+        convert_to,
+        metadata,
+        resize: (max_width.is_some() || max_height.is_some()).then_some(ResizeConfig {
+            max_width,
+            max_height,
+            fit,
+            filter: ResizeFilter::default(),
+        }),
+        responsive_widths,
+        target_ssim,
+        max_bytes,
+        downscale_to_fit,
+        webp_settings,
+        avif_settings,
+        jxl_settings,
+        svg_settings,
+        dry_run,
+        dry_run_sample,
+        incremental: manifest_path.is_some(),
+        manifest_path,
+    })
+}
+
+fn parse_number(flag: &str, value: Option<String>) -> Result<u8, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+    value
+        .parse::<u8>()
+        .map_err(|_| format!("{} expects a number, got {}", flag, value))
+}
+
+fn parse_pixels(flag: &str, value: Option<String>) -> Result<u32, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+    match value.parse::<u32>() {
+        Ok(px) if px > 0 => Ok(px),
+        _ => Err(format!(
+            "{} expects a positive pixel size, got {}",
+            flag, value
+        )),
+    }
+}
+
+fn parse_quality(flag: &str, value: Option<String>) -> Result<u8, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+    match value.parse::<u8>() {
+        Ok(q) if (10..=100).contains(&q) => Ok(q),
+        _ => Err(format!(
+            "{} must be between 10 and 100, got {}",
+            flag, value
+        )),
+    }
+}
+
+fn describe(stats: &FileStats) -> String {
+    let sizes = if stats.optimized_size > 0 {
+        format!(
+            "{} -> {}",
+            format_size(stats.original_size, DECIMAL),
+            format_size(stats.optimized_size, DECIMAL)
+        )
+    } else {
+        format_size(stats.original_size, DECIMAL)
+    };
+
+    let sizes = stats.qualities.iter().fold(sizes, |acc, q| {
+        let mut note = format!("{} q{}", q.format, q.quality);
+        if let Some(ssim) = q.ssim {
+            note.push_str(&format!(" ssim {:.4}", ssim));
+        }
+        if let Some((w, h)) = q.downscaled_to {
+            note.push_str(&format!(" {}x{}", w, h));
+        }
+        format!("{} [{}]", acc, note)
+    });
+    let sizes = match stats.webp_mode {
+        Some(WebpMode::Lossless) => format!("{} [webp lossless]", sizes),
+        Some(WebpMode::NearLossless) => format!("{} [webp near-lossless]", sizes),
+        _ => sizes,
+    };
+    let sizes = stats
+        .warnings
+        .iter()
+        .fold(sizes, |acc, w| format!("{} ({})", acc, w));
+
+    match &stats.outcome {
+        FileOutcome::Optimized => sizes,
+        FileOutcome::Unchanged => format!("{} (kept original)", sizes),
+        FileOutcome::Skipped(reason) => format!("skipped: {}", reason),
+        FileOutcome::Failed(reason) => format!("FAILED: {}", reason),
+    }
+}
+
+struct TerminalSink {
+    total: AtomicU64,
+    done: AtomicU64,
+}
+
+impl ProgressSink for TerminalSink {
+    fn status(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn file_start(&self, _file_name: &str) {}
+
+    fn progress(&self, payload: ProgressPayload) {
+        self.total.store(payload.total, Ordering::Relaxed);
+    }
+
+    fn file_done(&self, stats: &FileStats) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "[{}/{}] {}: {}",
+            done,
+            self.total.load(Ordering::Relaxed),
+            stats.path,
+            describe(stats)
+        );
+    }
+}
+
+fn check_tools() -> ExitCode {
+    let report = match tools::check_tools() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for tool in &report {
+        match (&tool.version, &tool.error) {
+            (Some(version), _) => println!(
+                "{}: {} ({})",
+                tool.name,
+                version,
+                tool.path.as_deref().unwrap_or("?")
+            ),
+            (None, error) => println!(
+                "{}: unavailable: {}",
+                tool.name,
+                error.as_deref().unwrap_or("unknown error")
+            ),
+        }
+    }
+
+    if report.iter().all(|t| t.available) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn undo(dir: &str, manifest: Option<&String>) -> ExitCode {
+    let report = match undo_last_run(Path::new(dir), manifest.map(Path::new)) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for path in &report.restored {
+        println!("restored: {}", path);
+    }
+    for failure in &report.failed {
+        eprintln!("failed: {}: {}", failure.path, failure.reason);
+    }
+    println!(
+        "Restored {} file(s) from backup {}",
+        report.restored.len(),
+        report.run_id
+    );
+
+    if report.failed.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn main() -> ExitCode {
+    if std::env::args().any(|a| a == "--check-tools") {
+        return check_tools();
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(pos) = args.iter().position(|a| a == "--undo") {
+        let manifest = args
+            .iter()
+            .position(|a| a == "--incremental")
+            .and_then(|pos| args.get(pos + 1));
+        return match args.get(pos + 1) {
+            Some(dir) => undo(dir, manifest),
+            None => {
+                eprintln!("error: --undo requires a directory\n\n{}", USAGE);
+                ExitCode::from(2)
+            }
+        };
+    }
+
+    let config = match parse_args(args) {
+        Ok(c) => c,
+        Err(e) => {
+            if e.is_empty() {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let sink = TerminalSink {
+        total: AtomicU64::new(0),
+        done: AtomicU64::new(0),
+    };
+
+    let result = match perform_optimization(&sink, config, Arc::new(AtomicBool::new(false))) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (verb, count) = if result.is_estimate {
+        println!(
+            "Estimated from {} of {} file(s)",
+            result.sampled_files, result.total_files
+        );
+        ("Projected for", result.total_files)
+    } else {
+        ("Processed", result.processed_files)
+    };
+    println!(
+        "{} {} file(s) in {:.1}s: {} -> {} (saved {}), {} kept original, {} skipped, {} failed",
+        verb,
+        count,
+        result.duration_total,
+        format_size(result.total_size_original, DECIMAL),
+        format_size(result.total_size_optimized, DECIMAL),
+        format_size(result.total_size_saved, DECIMAL),
+        result.kept_original_files,
+        result.skipped_files,
+        result.failed_files,
+    );
+    if result.total_size_webp > 0 {
+        println!(
+            "WebP total: {}",
+            format_size(result.total_size_webp, DECIMAL)
+        );
+    }
+    if result.total_size_avif > 0 {
+        println!(
+            "AVIF total: {}",
+            format_size(result.total_size_avif, DECIMAL)
+        );
+    }
+
+    if result.total_size_jxl > 0 {
+        println!(
+            "JPEG XL total: {}",
+            format_size(result.total_size_jxl, DECIMAL)
+        );
+    }
+    if let Some(run) = &result.backup_run {
+        println!("Originals backed up as run {}", run);
+    }
+
+    if result.failed_files > 0 {
+        for file in &result.files {
+            if let FileOutcome::Failed(reason) = &file.outcome {
+                eprintln!("failed: {}: {}", file.path, reason);
+            }
+        }
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
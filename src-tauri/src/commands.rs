@@ -1,15 +1,41 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use rayon::prelude::*;
 use base64::{engine::general_purpose, Engine as _};
 use image::ImageFormat;
 use std::io::Cursor;
 use std::sync::atomic::Ordering;
-use tauri::{command, Emitter, State, Window};
+use tauri::{command, AppHandle, Emitter, Manager, Runtime, State, Window};
 
-use crate::image_ops::ImageCache;
+use crate::backup;
+use crate::image_ops::{apply_orientation, ImageCache};
+use crate::metadata::read_metadata;
 use crate::optimizer::perform_optimization;
-use crate::types::{AppState, FinalResult, OptimizeConfig, FileNode};
+use crate::progress::ProgressSink;
+use crate::svg::render_svg;
+use crate::tools;
+use crate::types::{
+    AppState, FileStats, FinalResult, OptimizeConfig, FileNode, ProgressPayload, RestoreReport,
+    ToolStatus,
+};
+
+impl ProgressSink for Window {
+    fn status(&self, message: &str) {
+        let _ = self.emit("status_update", message);
+    }
+
+    fn file_start(&self, file_name: &str) {
+        let _ = self.emit("file_start", file_name);
+    }
+
+    fn progress(&self, payload: ProgressPayload) {
+        let _ = self.emit("progress", payload);
+    }
+
+    fn file_done(&self, stats: &FileStats) {
+        let _ = self.emit("file_done", stats);
+    }
+}
 
 #[command]
 pub fn get_last_result(state: State<'_, AppState>) -> Option<FinalResult> {
@@ -34,6 +60,11 @@ pub fn get_processing_state(state: State<'_, AppState>) -> bool {
         .unwrap_or_else(|e| e.into_inner())
 }
 
+#[command]
+pub fn check_tools() -> Result<Vec<ToolStatus>, String> {
+    tools::check_tools()
+}
+
 #[command]
 pub fn cancel_optimization(state: State<'_, AppState>) {
     state.should_cancel.store(true, Ordering::Relaxed);
@@ -50,8 +81,14 @@ pub async fn generate_thumbnail(
 
     let path_clone = path.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let img = image::open(&path_clone).map_err(|e| e.to_string())?;
-        let thumbnail = img.thumbnail(128, 128);
+        let source = Path::new(&path_clone);
+        let thumbnail = if source.extension().is_some_and(|e| e.eq_ignore_ascii_case("svg")) {
+            render_svg(source, 128)?
+        } else {
+            let img = image::open(source).map_err(|e| e.to_string())?;
+            let img = apply_orientation(img, read_metadata(source).orientation());
+            img.thumbnail(128, 128)
+        };
         let mut buffer = Cursor::new(Vec::new());
         thumbnail
             .write_to(&mut buffer, ImageFormat::Png)
@@ -69,9 +106,18 @@ pub async fn generate_thumbnail(
 #[command]
 pub async fn run_optimization(
     window: Window,
-    config: OptimizeConfig,
+    mut config: OptimizeConfig,
     state: State<'_, AppState>,
 ) -> Result<FinalResult, String> {
+    if config.backup {
+        let root = data_path(&window, config.backup_dir.take(), "backups")?;
+        config.backup_dir = Some(root.to_string_lossy().to_string());
+    }
+    if config.incremental {
+        let path = data_path(&window, config.manifest_path.take(), "manifest.json")?;
+        config.manifest_path = Some(path.to_string_lossy().to_string());
+    }
+
     {
         let mut processing = state
             .is_processing
@@ -127,11 +173,88 @@ pub async fn run_optimization(
     final_output
 }
 
+fn data_path<R: Runtime>(
+    manager: &impl Manager<R>,
+    custom: Option<String>,
+    name: &str,
+) -> Result<PathBuf, String> {
+    match custom {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => manager
+            .path()
+            .app_data_dir()
+            .map(|dir| dir.join(name))
+            .map_err(|e| format!("Failed to resolve app data folder: {}", e)),
+    }
+}
+
+// Restores rewrite originals, so they hold the same flag as an optimization
+// run for their whole duration
+fn claim_idle(state: &AppState) -> Result<(), String> {
+    let mut processing = state
+        .is_processing
+        .lock()
+        .map_err(|_| "Failed to lock state")?;
+    if *processing {
+        return Err("Another optimization or restore is in progress.".to_string());
+    }
+    *processing = true;
+    Ok(())
+}
+
+fn release(state: &AppState) {
+    if let Ok(mut processing) = state.is_processing.lock() {
+        *processing = false;
+    }
+}
+
+#[command]
+pub async fn undo_last_run(
+    app: AppHandle,
+    backup_dir: Option<String>,
+    manifest_path: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<RestoreReport, String> {
+    let root = data_path(&app, backup_dir, "backups")?;
+    let manifest = data_path(&app, manifest_path, "manifest.json")?;
+    claim_idle(&state)?;
+
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        backup::undo_last_run(&root, Some(&manifest))
+    })
+    .await;
+    release(&state);
+    result.map_err(|e| e.to_string())?
+}
+
+#[command]
+pub async fn restore_files(
+    app: AppHandle,
+    run_id: String,
+    paths: Vec<String>,
+    backup_dir: Option<String>,
+    manifest_path: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<RestoreReport, String> {
+    let root = data_path(&app, backup_dir, "backups")?;
+    let manifest = data_path(&app, manifest_path, "manifest.json")?;
+    claim_idle(&state)?;
+
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        backup::restore_files(&root, &run_id, Some(&paths), Some(&manifest))
+    })
+    .await;
+    release(&state);
+    result.map_err(|e| e.to_string())?
+}
 
 fn is_image(path: &Path) -> bool {
     if let Some(ext) = path.extension() {
         let ext_str = ext.to_string_lossy().to_lowercase();
-        return ["jpg", "jpeg", "png"].contains(&ext_str.as_str());
+        return [
+            "jpg", "jpeg", "png", "gif", "tif", "tiff", "bmp", "tga", "webp", "avif", "svg",
+        ]
+        .contains(&ext_str.as_str());
     }
     false
 }
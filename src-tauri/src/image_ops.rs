@@ -1,19 +1,236 @@
-use crate::tools::{get_tool_ref, ToolPath};
-use image::{DynamicImage, GenericImageView};
+use crate::metadata::{embed_png_metadata, embed_webp_metadata, write_jpeg_metadata, Metadata};
+use crate::quality::{encode_with_target, fit_budget, Encoded, QualityTarget, SizeBudget};
+use crate::svg::minify_svg;
+use crate::tools::{command, get_avif_decoder, get_jxl_encoder, get_tool_ref, ToolPath};
+use crate::types::{
+    AvifBitDepth, AvifSettings, FitMode, JxlMode, JxlSettings, PngBackend, PngInterlace,
+    PngSettings, ResizeConfig, ResizeFilter, SvgSettings, WebpMode, WebpSettings,
+};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image::imageops::FilterType;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageDecoder, ImageEncoder, ImageFormat,
+    RgbImage, RgbaImage,
+};
 use moka::future::Cache;
 use rgb::FromSlice;
+use std::collections::HashSet;
 use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::num::NonZeroU8;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct ImageCache(pub Cache<String, String>);
 
-pub fn process_jpg(path: &Path, quality: u8) -> u64 {
-    let img = match image::open(path) {
-        Ok(i) => i.to_rgb8(),
-        Err(_) => return fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+const QUALITY_FLOOR_MISSED: &str = "Quality floor not reachable, lossless only";
+const QUANTIZED_LARGER: &str = "Quantized result was larger, lossless only";
+
+pub struct PngOptions {
+    pub backend: PngBackend,
+    pub pngquant: ToolPath,
+    pub oxipng: ToolPath,
+    pub min_quality: u8,
+    pub max_quality: u8,
+    pub settings: PngSettings,
+    pub timeout: Option<Duration>,
+    pub cancel: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub struct WriteResult {
+    pub size: u64,
+    pub kept_original: bool,
+    pub quality: Option<u8>,
+    pub score: Option<f64>,
+    pub downscaled: Option<(u32, u32)>,
+    pub warnings: Vec<String>,
+    pub tool_log: Vec<String>,
+}
+
+impl WriteResult {
+    fn kept(size: u64) -> Self {
+        WriteResult {
+            size,
+            kept_original: true,
+            ..Default::default()
+        }
+    }
+}
+
+// Writes to a sibling temp file and renames it over the target, so an
+// interrupted run never leaves a truncated image behind.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".opt-");
+    // New outputs get the usual umask-limited mode instead of the owner-only temp default
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(fs::Permissions::from_mode(0o666));
+    }
+    let mut tmp = builder
+        .tempfile_in(dir)
+        .map_err(|e| format!("Failed to create temp file: {}", e))?;
+
+    tmp.write_all(data)
+        .and_then(|_| keep_permissions(path, tmp.as_file()))
+        .and_then(|_| tmp.as_file().sync_all())
+        .map_err(|e| format!("Failed to write temp file: {}", e))?;
+    tmp.persist(path)
+        .map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    // The rename lives in the directory, which needs its own sync to survive a crash
+    #[cfg(unix)]
+    fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    Ok(())
+}
+
+// Temp files are created owner-only, so carry over the mode of the file being replaced
+fn keep_permissions(target: &Path, file: &fs::File) -> std::io::Result<()> {
+    match fs::metadata(target) {
+        Ok(m) => file.set_permissions(m.permissions()),
+        Err(_) => Ok(()),
+    }
+}
+
+// Without a path nothing is written and only the resulting size is reported
+fn write_if_smaller(
+    path: Option<&Path>,
+    data: &[u8],
+    original_size: u64,
+) -> Result<WriteResult, String> {
+    if data.is_empty() || data.len() as u64 >= original_size {
+        return Ok(WriteResult::kept(original_size));
+    }
+
+    if let Some(path) = path {
+        write_atomic(path, data)?;
+    }
+    Ok(WriteResult {
+        size: data.len() as u64,
+        ..Default::default()
+    })
+}
+
+// WebP goes through libwebp like the encoder. No AVIF decoder is linked in,
+// so AVIF sources are converted by libavif's avifdec.
+pub fn decode_image(path: &Path, opts: &PngOptions) -> Result<DynamicImage, String> {
+    let ext = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    match ext.as_str() {
+        "webp" => {
+            let data = fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
+            let decoded = webp::Decoder::new(&data)
+                .decode()
+                .ok_or_else(|| "Failed to decode image: invalid WebP".to_string())?;
+            let (width, height) = (decoded.width(), decoded.height());
+            let img = if decoded.is_alpha() {
+                RgbaImage::from_raw(width, height, decoded.to_vec()).map(DynamicImage::ImageRgba8)
+            } else {
+                RgbImage::from_raw(width, height, decoded.to_vec()).map(DynamicImage::ImageRgb8)
+            };
+            img.ok_or_else(|| "Failed to decode image: invalid WebP".to_string())
+        }
+        "avif" => decode_avif(path, opts),
+        _ => image::open(path).map_err(|e| format!("Failed to decode image: {}", e)),
+    }
+}
+
+fn decode_avif(path: &Path, opts: &PngOptions) -> Result<DynamicImage, String> {
+    let work = tempfile::Builder::new()
+        .prefix(".opt-")
+        .suffix(".png")
+        .tempfile()
+        .map_err(|e| format!("Failed to create temp file: {}", e))?
+        .into_temp_path();
+
+    let mut cmd = command(get_tool_ref(&get_avif_decoder()));
+    cmd.args(["--depth", "8"]).arg(path).arg(&work);
+
+    let limits = ToolLimits::new(opts);
+    run_tool("avifdec", &mut cmd, &[], &limits)?;
+
+    image::open(&work).map_err(|e| format!("Failed to decode image: {}", e))
+}
+
+// VP8X flag bit 1 marks an animation
+pub fn is_animated_webp(path: &Path) -> bool {
+    let mut header = [0u8; 21];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok_and(|_| &header[12..16] == b"VP8X" && header[20] & 0x02 != 0)
+}
+
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+pub fn resize_image(img: DynamicImage, resize: &ResizeConfig) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let box_w = resize.max_width.unwrap_or(width).min(width).max(1);
+    let box_h = resize.max_height.unwrap_or(height).min(height).max(1);
+
+    if box_w == width && box_h == height {
+        return img;
+    }
+
+    // With one side open there is no box to crop or stretch to, only a scale
+    let fit = match (resize.max_width, resize.max_height) {
+        (Some(_), Some(_)) => resize.fit,
+        _ => FitMode::Contain,
     };
 
+    let filter = resize.filter.into();
+    match fit {
+        FitMode::Contain => img.resize(box_w, box_h, filter),
+        FitMode::Cover => img.resize_to_fill(box_w, box_h, filter),
+        FitMode::Fill => img.resize_exact(box_w, box_h, filter),
+    }
+}
+
+pub fn resize_to_width(img: &DynamicImage, width: u32, filter: ResizeFilter) -> DynamicImage {
+    let height = ((img.height() as u64 * width as u64) / img.width().max(1) as u64).max(1) as u32;
+    img.resize_exact(width, height, filter.into())
+}
+
+pub fn encode_jpeg(img: &DynamicImage, quality: u8, meta: &Metadata) -> Result<Vec<u8>, String> {
+    let img = img.to_rgb8();
     let (width, height) = img.dimensions();
     let pixels = img.as_raw();
 
@@ -23,117 +240,987 @@ pub fn process_jpg(path: &Path, quality: u8) -> u64 {
     comp.set_progressive_mode();
     comp.set_optimize_scans(true);
 
-    let mut comp = match comp.start_compress(Vec::new()) {
-        Ok(c) => c,
-        Err(_) => return fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+    let mut comp = comp
+        .start_compress(Vec::new())
+        .map_err(|e| format!("JPEG encoder failed: {}", e))?;
+
+    write_jpeg_metadata(&mut comp, meta);
+
+    comp.write_scanlines(pixels)
+        .map_err(|e| format!("JPEG encoder failed: {}", e))?;
+
+    comp.finish()
+        .map_err(|e| format!("JPEG encoder failed: {}", e))
+}
+
+pub fn encode_png(img: &DynamicImage) -> Result<Vec<u8>, String> {
+    let converted;
+    let img = match img {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            converted = DynamicImage::ImageRgba8(img.to_rgba8());
+            &converted
+        }
+        _ => img,
     };
 
-    if comp.write_scanlines(pixels).is_err() {
-        return fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let mut out = Vec::new();
+    PngEncoder::new_with_quality(&mut out, CompressionType::Best, PngFilter::Adaptive)
+        .write_image(img.as_bytes(), img.width(), img.height(), img.color())
+        .map_err(|e| format!("PNG encoder failed: {}", e))?;
+    Ok(out)
+}
+
+// Screenshots, logos and pixel art: a small palette or mostly flat horizontal runs
+pub fn looks_like_graphic(img: &DynamicImage) -> bool {
+    let rgba = img.to_rgba8();
+    let step = ((rgba.width() as u64 * rgba.height() as u64) / 250_000).max(1) as usize;
+
+    let mut colors = HashSet::new();
+    let mut flat = 0u64;
+    let mut pairs = 0u64;
+    for row in rgba.rows().step_by(step) {
+        let mut prev = None;
+        for px in row {
+            if colors.len() <= 256 {
+                colors.insert(px.0);
+            }
+            if let Some(prev) = prev {
+                flat += (prev == px) as u64;
+                pairs += 1;
+            }
+            prev = Some(px);
+        }
     }
 
-    let compressed_data = match comp.finish() {
-        Ok(d) => d,
-        Err(_) => return fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+    colors.len() <= 256 || (pairs > 0 && flat * 2 > pairs)
+}
+
+pub fn encode_webp(
+    img: &DynamicImage,
+    quality: u8,
+    settings: &WebpSettings,
+    meta: &Metadata,
+) -> Result<Vec<u8>, String> {
+    let (width, height) = img.dimensions();
+    let config = webp_config(quality, settings)?;
+
+    let memory = match img {
+        DynamicImage::ImageRgba8(buf) => {
+            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode_advanced(&config)
+        }
+        DynamicImage::ImageRgb8(buf) => {
+            webp::Encoder::from_rgb(buf.as_raw(), width, height).encode_advanced(&config)
+        }
+        _ => {
+            let buf = img.to_rgba8();
+            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode_advanced(&config)
+        }
+    }
+    .map_err(|e| format!("WebP encoder failed: {:?}", e))?;
+
+    Ok(embed_webp_metadata(&memory, width, height, meta))
+}
+
+fn webp_config(quality: u8, settings: &WebpSettings) -> Result<webp::WebPConfig, String> {
+    let mut config =
+        webp::WebPConfig::new().map_err(|_| "Failed to initialize WebP encoder".to_string())?;
+    config.quality = quality as f32;
+    match settings.mode {
+        WebpMode::Lossless => config.lossless = 1,
+        WebpMode::NearLossless => {
+            config.lossless = 1;
+            config.near_lossless = settings.near_lossless as i32;
+        }
+        WebpMode::Lossy | WebpMode::Auto => config.lossless = 0,
+    }
+    config.method = settings.method as i32;
+    config.alpha_quality = settings.alpha_quality as i32;
+    Ok(config)
+}
+
+pub fn encode_avif(
+    img: &DynamicImage,
+    quality: u8,
+    settings: &AvifSettings,
+    meta: &Metadata,
+) -> Result<Vec<u8>, String> {
+    let rgba = img.to_rgba8();
+    let (width, height) = img.dimensions();
+
+    let src_img = imgref::Img::new(rgba.as_raw().as_rgba(), width as usize, height as usize);
+
+    let bit_depth = match settings.bit_depth {
+        AvifBitDepth::Eight => ravif::BitDepth::Eight,
+        AvifBitDepth::Ten => ravif::BitDepth::Ten,
+        AvifBitDepth::Auto => ravif::BitDepth::Auto,
     };
 
-    if fs::write(path, &compressed_data).is_ok() {
-        compressed_data.len() as u64
-    } else {
-        fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+    let mut encoder = ravif::Encoder::new()
+        .with_quality(quality.clamp(1, 100) as f32)
+        .with_speed(settings.speed)
+        .with_alpha_quality(settings.alpha_quality as f32)
+        .with_bit_depth(bit_depth);
+
+    // ravif can only carry EXIF; ICC and XMP are not written to AVIF
+    if let Some(exif) = &meta.exif {
+        encoder = encoder.with_exif(exif.as_slice());
+    }
+
+    encoder
+        .encode_rgba(src_img)
+        .map(|encoded| encoded.avif_file)
+        .map_err(|e| format!("AVIF encoder failed: {}", e))
+}
+
+pub fn encode_jpeg_within(
+    img: &DynamicImage,
+    quality: QualityTarget,
+    budget: Option<&SizeBudget>,
+    meta: &Metadata,
+) -> Result<Encoded, String> {
+    let encode = |img: &DynamicImage, q: u8| encode_jpeg(img, q, meta);
+    let encoded = encode_with_target(img, quality, ImageFormat::Jpeg, |q| encode(img, q))?;
+    fit_budget(img, encoded, budget, encode)
+}
+
+pub fn process_jpg(
+    img: &DynamicImage,
+    path: Option<&Path>,
+    quality: QualityTarget,
+    budget: Option<&SizeBudget>,
+    original_size: u64,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    let encoded = encode_jpeg_within(img, quality, budget, meta)?;
+    write_encoded(path, encoded, original_size)
+}
+
+// Lossless rebuild that keeps frame pixels and palettes, dropping comments and interlacing
+pub fn optimize_gif(data: &[u8]) -> Result<Vec<u8>, String> {
+    let gif_err = |e: gif::DecodingError| format!("Failed to decode GIF: {}", e);
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(gif_err)?;
+    let global = decoder.global_palette().map(|p| p.to_vec());
+    let (width, height) = (decoder.width(), decoder.height());
+
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(gif_err)? {
+        let mut frame = frame.clone();
+        frame.interlaced = false;
+        if frame.palette.is_some() && frame.palette == global {
+            frame.palette = None;
+        }
+        frames.push(frame);
+    }
+
+    let enc_err = |e: gif::EncodingError| format!("GIF encoder failed: {}", e);
+    let mut out = Vec::new();
+    {
+        let palette = global.as_deref().unwrap_or_default();
+        let mut encoder = gif::Encoder::new(&mut out, width, height, palette).map_err(enc_err)?;
+        if frames.len() > 1 {
+            encoder.set_repeat(decoder.repeat()).map_err(enc_err)?;
+        }
+        for frame in &frames {
+            encoder.write_frame(frame).map_err(enc_err)?;
+        }
     }
+    Ok(out)
+}
+
+pub fn process_gif(
+    source: &Path,
+    dest: Option<&Path>,
+    original_size: u64,
+) -> Result<WriteResult, String> {
+    let data = fs::read(source).map_err(|e| format!("Failed to read file: {}", e))?;
+    write_if_smaller(dest, &optimize_gif(&data)?, original_size)
+}
+
+pub fn process_svg(
+    source: &Path,
+    dest: Option<&Path>,
+    original_size: u64,
+    settings: &SvgSettings,
+) -> Result<WriteResult, String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("Failed to read file: {}", e))?;
+    write_if_smaller(dest, minify_svg(&text, settings)?.as_bytes(), original_size)
 }
 
-pub fn process_png(path: &Path, pq: &ToolPath, oxi: &ToolPath, min: u8, max: u8) -> u64 {
-    run_pngquant(path, pq, min, max);
-    run_oxipng(path, oxi);
-    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<(RgbaImage, u32)>,
+    pub loop_count: i32,
 }
 
-fn run_pngquant(path: &Path, tool: &ToolPath, min: u8, max: u8) {
-    #[cfg(target_os = "windows")]
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
+// Returns None for single-frame GIFs, which go through the still image paths
+pub fn decode_gif_animation(path: &Path) -> Result<Option<Animation>, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
+    let gif_err = |e: image::ImageError| format!("Failed to decode GIF: {}", e);
+
+    let decoder = GifDecoder::new(Cursor::new(&data)).map_err(gif_err)?;
+    let (width, height) = decoder.dimensions();
+    let frames = decoder.into_frames().collect_frames().map_err(gif_err)?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let delay = numer / denom.max(1);
+            // Browsers play near-zero GIF delays at 100ms, so keep what viewers actually saw
+            (frame.into_buffer(), if delay <= 10 { 100 } else { delay })
+        })
+        .collect();
+
+    Ok(Some(Animation {
+        width,
+        height,
+        frames,
+        loop_count: gif_loop_count(&data)?,
+    }))
+}
+
+// WebP counts plays with 0 meaning forever; GIF counts extra repeats
+fn gif_loop_count(data: &[u8]) -> Result<i32, String> {
+    let gif_err = |e: gif::DecodingError| format!("Failed to decode GIF: {}", e);
+    let mut decoder = gif::DecodeOptions::new().read_info(data).map_err(gif_err)?;
+    // The loop extension sits before the first frame
+    decoder.next_frame_info().map_err(gif_err)?;
+
+    Ok(match decoder.repeat() {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(n) => n as i32 + 1,
+    })
+}
+
+pub fn encode_animated_webp(
+    anim: &Animation,
+    quality: u8,
+    settings: &WebpSettings,
+) -> Result<Vec<u8>, String> {
+    let config = webp_config(quality, settings)?;
+    let mut encoder = webp::AnimEncoder::new(anim.width, anim.height, &config);
+    encoder.set_loop_count(anim.loop_count);
+
+    let mut timestamp = 0;
+    for (frame, delay) in &anim.frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.as_raw(),
+            anim.width,
+            anim.height,
+            timestamp,
+        ));
+        timestamp += *delay as i32;
+    }
+
+    let memory = encoder
+        .try_encode()
+        .map_err(|e| format!("WebP encoder failed: {:?}", e))?;
+    let mut data = memory.to_vec();
+    fix_last_frame_duration(&mut data, timestamp as u32);
+    Ok(data)
+}
+
+// The encoder closes the animation without an end timestamp, so libwebp
+// guesses the last frame's duration from the average. Patch in the real one.
+fn fix_last_frame_duration(data: &mut [u8], total_ms: u32) {
+    let mut pos = 12;
+    let mut elapsed = 0u32;
+    let mut last = None;
+
+    while pos + 8 <= data.len() {
+        let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        if &data[pos..pos + 4] == b"ANMF" && pos + 8 + 16 <= data.len() {
+            let field = pos + 8 + 12;
+            if let Some(prev) = last.replace(field) {
+                elapsed += u32::from_le_bytes([data[prev], data[prev + 1], data[prev + 2], 0]);
+            }
+        }
+        pos += 8 + len + (len & 1);
+    }
+
+    if let Some(field) = last {
+        let duration = total_ms.saturating_sub(elapsed).min(0xFF_FFFF);
+        data[field..field + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+// Reads `source` unless pixels are given and writes the result to `dest`, if any
+pub fn process_png(
+    source: &Path,
+    dest: Option<&Path>,
+    pixels: Option<&DynamicImage>,
+    original_size: u64,
+    opts: &PngOptions,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    let res = match opts.backend {
+        PngBackend::Native => process_png_native(source, pixels, opts, meta),
+        PngBackend::External => process_png_external(source, pixels, opts, meta),
+    }?;
+
+    let mut out = write_if_smaller(dest, &res.data, original_size)?;
+    out.warnings = res.warnings;
+    out.tool_log = res.tool_log;
+    Ok(out)
+}
 
-    let mut cmd = Command::new(get_tool_ref(tool));
+struct PngResult {
+    data: Vec<u8>,
+    warnings: Vec<String>,
+    tool_log: Vec<String>,
+}
+
+fn process_png_native(
+    source: &Path,
+    pixels: Option<&DynamicImage>,
+    opts: &PngOptions,
+    meta: &Metadata,
+) -> Result<PngResult, String> {
+    let (original, decoded) = match pixels {
+        Some(img) => (encode_png(img)?, None),
+        None => {
+            let data = fs::read(source).map_err(|e| format!("Failed to read file: {}", e))?;
+            let img = image::load_from_memory_with_format(&data, ImageFormat::Png)
+                .map_err(|e| format!("Failed to decode PNG: {}", e))?;
+            (data, Some(img))
+        }
+    };
+
+    let quantized = match pixels.or(decoded.as_ref()) {
+        Some(img) => quantize_png(img, opts)?,
+        None => None,
+    };
+
+    let mut data = optimize_png_lossless(quantized.as_deref().unwrap_or(&original), opts)?;
+    if !meta.is_empty() {
+        data = embed_png_metadata(&data, meta);
+    }
+
+    let mut warnings = Vec::new();
+    if quantized.is_none() {
+        warnings.push(QUALITY_FLOOR_MISSED.to_string());
+    }
+    Ok(PngResult {
+        data,
+        warnings,
+        tool_log: Vec::new(),
+    })
+}
+
+fn quantize_png(img: &DynamicImage, opts: &PngOptions) -> Result<Option<Vec<u8>>, String> {
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let quant_err = |e: imagequant::Error| format!("PNG quantization failed: {}", e);
+
+    let mut liq = imagequant::new();
+    liq.set_speed(opts.settings.speed as i32)
+        .map_err(quant_err)?;
+    liq.set_quality(opts.min_quality, opts.max_quality)
+        .map_err(quant_err)?;
+    liq.set_min_posterization(opts.settings.posterize)
+        .map_err(quant_err)?;
+
+    let mut image = liq
+        .new_image_borrowed(
+            rgba.as_raw().as_rgba(),
+            width as usize,
+            height as usize,
+            0.0,
+        )
+        .map_err(quant_err)?;
+
+    let mut res = match liq.quantize(&mut image) {
+        Ok(res) => res,
+        // Same as pngquant exiting with 99: keep the lossless pixels
+        Err(imagequant::Error::QualityTooLow) => return Ok(None),
+        Err(e) => return Err(quant_err(e)),
+    };
+    res.set_dithering_level(opts.settings.dithering)
+        .map_err(quant_err)?;
+    let (palette, indices) = res.remapped(&mut image).map_err(quant_err)?;
+
+    let png_err = |e: png::EncodingError| format!("PNG encoder failed: {}", e);
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|c| [c.r, c.g, c.b])
+            .collect::<Vec<u8>>(),
+    );
+    if palette.iter().any(|c| c.a < 255) {
+        encoder.set_trns(palette.iter().map(|c| c.a).collect::<Vec<u8>>());
+    }
+
+    let mut writer = encoder.write_header().map_err(png_err)?;
+    writer.write_image_data(&indices).map_err(png_err)?;
+    writer.finish().map_err(png_err)?;
+
+    Ok(Some(out))
+}
+
+fn optimize_png_lossless(data: &[u8], opts: &PngOptions) -> Result<Vec<u8>, String> {
+    let settings = &opts.settings;
+    let mut options = oxipng::Options::from_preset(settings.oxipng_level);
+    options.strip = oxipng::StripChunks::All;
+    options.timeout = opts.timeout;
+    options.interlace = match settings.interlace {
+        PngInterlace::Off => Some(oxipng::Interlacing::None),
+        PngInterlace::Adam7 => Some(oxipng::Interlacing::Adam7),
+        PngInterlace::Keep => None,
+    };
+    if settings.zopfli {
+        options.deflate = oxipng::Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap_or(NonZeroU8::MIN),
+        };
+    }
+    oxipng::optimize_from_memory(data, &options).map_err(|e| format!("oxipng failed: {}", e))
+}
+
+fn process_png_external(
+    source: &Path,
+    pixels: Option<&DynamicImage>,
+    opts: &PngOptions,
+    meta: &Metadata,
+) -> Result<PngResult, String> {
+    let work = tempfile::Builder::new()
+        .prefix(".opt-")
+        .suffix(".png")
+        .tempfile()
+        .map_err(|e| format!("Failed to create temp file: {}", e))?
+        .into_temp_path();
+
+    match pixels {
+        Some(img) => fs::write(&work, encode_png(img)?),
+        None => fs::copy(source, &work).map(|_| ()),
+    }
+    .map_err(|e| format!("Failed to create temp file: {}", e))?;
+
+    let limits = ToolLimits::new(opts);
+    let quant = run_pngquant(&work, opts, &limits)?;
+    let oxi = run_oxipng(&work, opts, &limits)?;
+
+    let mut warnings = Vec::new();
+    match quant.code {
+        98 => warnings.push(QUANTIZED_LARGER.to_string()),
+        99 => warnings.push(QUALITY_FLOOR_MISSED.to_string()),
+        _ => {}
+    }
+    let tool_log = [("pngquant", quant.stderr), ("oxipng", oxi.stderr)]
+        .into_iter()
+        .filter(|(_, stderr)| !stderr.is_empty())
+        .map(|(name, stderr)| format!("{}: {}", name, stderr))
+        .collect();
+
+    let mut data = fs::read(&work).map_err(|e| format!("Failed to read temp file: {}", e))?;
+    if !meta.is_empty() {
+        data = embed_png_metadata(&data, meta);
+    }
+
+    Ok(PngResult {
+        data,
+        warnings,
+        tool_log,
+    })
+}
+
+struct ToolOutput {
+    code: i32,
+    stderr: String,
+}
+
+struct ToolLimits<'a> {
+    deadline: Option<Instant>,
+    timeout: Option<Duration>,
+    cancel: &'a AtomicBool,
+}
+
+impl<'a> ToolLimits<'a> {
+    fn new(opts: &'a PngOptions) -> Self {
+        ToolLimits {
+            deadline: opts.timeout.map(|t| Instant::now() + t),
+            timeout: opts.timeout,
+            cancel: &opts.cancel,
+        }
+    }
+}
+
+fn run_tool(
+    name: &str,
+    cmd: &mut Command,
+    allowed_codes: &[i32],
+    limits: &ToolLimits,
+) -> Result<ToolOutput, String> {
+    let mut child = cmd
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", name, e))?;
+
+    // Drain stderr on a side thread so a chatty tool cannot block on a full pipe
+    let stderr_pipe = child.stderr.take();
+    let reader = thread::spawn(move || {
+        let mut buf = String::new();
+        if let Some(mut pipe) = stderr_pipe {
+            let _ = pipe.read_to_string(&mut buf);
+        }
+        buf
+    });
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => {}
+            Err(e) => return Err(format!("Failed to wait for {}: {}", name, e)),
+        }
+
+        let stop_reason = if limits.cancel.load(Ordering::Relaxed) {
+            Some("Canceled".to_string())
+        } else if limits.deadline.is_some_and(|d| Instant::now() >= d) {
+            let secs = limits.timeout.unwrap_or_default().as_secs();
+            Some(format!("{} timed out after {}s", name, secs))
+        } else {
+            None
+        };
+
+        if let Some(reason) = stop_reason {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(reason);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stderr = reader.join().unwrap_or_default().trim().to_string();
+
+    match status.code() {
+        Some(code) if code == 0 || allowed_codes.contains(&code) => Ok(ToolOutput { code, stderr }),
+        code => {
+            let code = code.map_or("signal".to_string(), |c| c.to_string());
+            Err(format!("{} exited with {}: {}", name, code, stderr))
+        }
+    }
+}
+
+fn run_pngquant(path: &Path, opts: &PngOptions, limits: &ToolLimits) -> Result<ToolOutput, String> {
+    let settings = &opts.settings;
+    let mut cmd = command(get_tool_ref(&opts.pngquant));
     cmd.args([
-        &format!("--quality={}-{}", min, max),
-        "--speed=3",
+        &format!("--quality={}-{}", opts.min_quality, opts.max_quality),
+        &format!("--speed={}", settings.speed),
         "--force",
         "--ext=.png",
-    ])
-    .arg(path);
+    ]);
+    if settings.dithering > 0.0 {
+        cmd.arg(format!("--floyd={}", settings.dithering));
+    } else {
+        cmd.arg("--nofs");
+    }
+    if settings.posterize > 0 {
+        cmd.arg(format!("--posterize={}", settings.posterize));
+    }
+    cmd.arg(path);
+
+    // 98/99: quality target not reachable, file left untouched
+    run_tool("pngquant", &mut cmd, &[98, 99], limits)
+}
+
+fn run_oxipng(path: &Path, opts: &PngOptions, limits: &ToolLimits) -> Result<ToolOutput, String> {
+    let settings = &opts.settings;
+    let interlace = match settings.interlace {
+        PngInterlace::Off => "0",
+        PngInterlace::Adam7 => "1",
+        PngInterlace::Keep => "keep",
+    };
 
-    #[cfg(target_os = "windows")]
-    use std::os::windows::process::CommandExt;
-    #[cfg(target_os = "windows")]
-    cmd.creation_flags(CREATE_NO_WINDOW);
+    let mut cmd = command(get_tool_ref(&opts.oxipng));
+    cmd.args(["-o", &settings.oxipng_level.to_string()])
+        .args(["--strip", "all", "-t", "1", "-i", interlace]);
+    if settings.zopfli {
+        cmd.arg("--zopfli");
+    }
+    cmd.arg(path);
 
-    let _ = cmd.output();
+    run_tool("oxipng", &mut cmd, &[], limits)
 }
 
-fn run_oxipng(path: &Path, tool: &ToolPath) {
-    #[cfg(target_os = "windows")]
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
+pub fn generate_webp(
+    img: &DynamicImage,
+    path: Option<&Path>,
+    quality: QualityTarget,
+    settings: &WebpSettings,
+    budget: Option<&SizeBudget>,
+    original_size: u64,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    let webp_path = path.map(|p| p.with_extension("webp"));
+    let res = process_webp(
+        img,
+        webp_path.as_deref(),
+        quality,
+        settings,
+        budget,
+        original_size,
+        meta,
+    )?;
+    Ok(sidecar_result(res))
+}
 
-    let mut cmd = Command::new(get_tool_ref(tool));
-    cmd.args(["-o", "4", "--strip", "all", "-t", "1"]).arg(path);
+pub fn process_webp(
+    img: &DynamicImage,
+    path: Option<&Path>,
+    quality: QualityTarget,
+    settings: &WebpSettings,
+    budget: Option<&SizeBudget>,
+    original_size: u64,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    let encode = |img: &DynamicImage, q: u8| encode_webp(img, q, settings, meta);
+    let encoded = encode_with_target(img, quality, ImageFormat::WebP, |q| encode(img, q))?;
+    let encoded = fit_budget(img, encoded, budget, encode)?;
+    write_encoded(path, encoded, original_size)
+}
 
-    #[cfg(target_os = "windows")]
-    use std::os::windows::process::CommandExt;
-    #[cfg(target_os = "windows")]
-    cmd.creation_flags(CREATE_NO_WINDOW);
+pub fn generate_avif(
+    img: &DynamicImage,
+    path: Option<&Path>,
+    settings: &AvifSettings,
+    budget: Option<&SizeBudget>,
+    original_size: u64,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    let avif_path = path.map(|p| p.with_extension("avif"));
+    let res = process_avif(
+        img,
+        avif_path.as_deref(),
+        settings,
+        budget,
+        original_size,
+        meta,
+    )?;
+    Ok(sidecar_result(res))
+}
 
-    let _ = cmd.output();
+pub fn process_avif(
+    img: &DynamicImage,
+    path: Option<&Path>,
+    settings: &AvifSettings,
+    budget: Option<&SizeBudget>,
+    original_size: u64,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    // Encoded AVIF is not decoded back for scoring, so only the byte budget can steer quality
+    let encode = |img: &DynamicImage, q: u8| encode_avif(img, q, settings, meta);
+    let quality = QualityTarget::Fixed(settings.quality);
+    let encoded = encode_with_target(img, quality, ImageFormat::Avif, |q| encode(img, q))?;
+    let encoded = fit_budget(img, encoded, budget, encode)?;
+    write_encoded(path, encoded, original_size)
 }
 
-pub fn generate_webp(img: &DynamicImage, path: &Path, quality: f32) -> u64 {
-    let webp_path = path.with_extension("webp");
-    let (width, height) = img.dimensions();
+pub enum JxlSource<'a> {
+    // Recompressed without loss; djxl rebuilds the exact original file
+    Jpeg(&'a Path),
+    // cjxl reads GIFs itself, so animations keep their frames
+    Gif(&'a Path),
+    Pixels(&'a DynamicImage),
+}
 
-    let memory = match img {
-        DynamicImage::ImageRgba8(buf) => {
-            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode(quality)
-        }
-        DynamicImage::ImageRgb8(buf) => {
-            webp::Encoder::from_rgb(buf.as_raw(), width, height).encode(quality)
+pub fn generate_jxl(
+    source: JxlSource,
+    path: Option<&Path>,
+    settings: &JxlSettings,
+    opts: &PngOptions,
+    original_size: u64,
+    meta: &Metadata,
+) -> Result<WriteResult, String> {
+    let data = encode_jxl(source, settings, opts, meta)?;
+    let jxl_path = path.map(|p| p.with_extension("jxl"));
+    let res = write_if_smaller(jxl_path.as_deref(), &data, original_size)?;
+    Ok(sidecar_result(res))
+}
+
+// No JPEG XL encoder is linked in, so this runs libjxl's cjxl on temp files
+pub fn encode_jxl(
+    source: JxlSource,
+    settings: &JxlSettings,
+    opts: &PngOptions,
+    meta: &Metadata,
+) -> Result<Vec<u8>, String> {
+    let work = tempfile::Builder::new()
+        .prefix(".opt-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create temp folder: {}", e))?;
+    let output = work.path().join("out.jxl");
+
+    let mut cmd = command(get_tool_ref(&get_jxl_encoder()));
+    let input = match source {
+        JxlSource::Jpeg(path) => {
+            cmd.arg("--lossless_jpeg=1");
+            path.to_path_buf()
         }
-        _ => {
-            let buf = img.to_rgba8();
-            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode(quality)
+        JxlSource::Gif(path) => path.to_path_buf(),
+        JxlSource::Pixels(img) => {
+            let input = work.path().join("in.png");
+            let mut data = encode_png(img)?;
+            if !meta.is_empty() {
+                data = embed_png_metadata(&data, meta);
+            }
+            fs::write(&input, data).map_err(|e| format!("Failed to write temp file: {}", e))?;
+            input
         }
     };
+    if !matches!(source, JxlSource::Jpeg(_)) {
+        match settings.mode {
+            JxlMode::Lossy => cmd.args(["-q", &settings.quality.to_string()]),
+            JxlMode::Lossless => cmd.args(["-d", "0"]),
+        };
+    }
+    cmd.args(["-e", &settings.effort.to_string(), "--quiet"])
+        .arg(&input)
+        .arg(&output);
+
+    run_tool("cjxl", &mut cmd, &[], &ToolLimits::new(opts))?;
+    fs::read(&output).map_err(|e| format!("Failed to read temp file: {}", e))
+}
+
+pub fn generate_animated_webp(
+    anim: &Animation,
+    path: Option<&Path>,
+    settings: &WebpSettings,
+    original_size: u64,
+) -> Result<WriteResult, String> {
+    // Quality search would need every frame decoded per step, so animations use the fixed quality
+    let encoded = Encoded {
+        data: encode_animated_webp(anim, settings.quality, settings)?,
+        quality: settings.quality,
+        score: None,
+        downscaled: None,
+    };
+    let webp_path = path.map(|p| p.with_extension("webp"));
+    let res = write_encoded(webp_path.as_deref(), encoded, original_size)?;
+    Ok(sidecar_result(res))
+}
+
+fn write_encoded(
+    path: Option<&Path>,
+    encoded: Encoded,
+    original_size: u64,
+) -> Result<WriteResult, String> {
+    let res = write_if_smaller(path, &encoded.data, original_size)?;
+    Ok(WriteResult {
+        quality: Some(encoded.quality),
+        score: encoded.score,
+        downscaled: encoded.downscaled,
+        ..res
+    })
+}
 
-    if fs::write(&webp_path, &*memory).is_ok() {
-        memory.len() as u64
+fn sidecar_result(res: WriteResult) -> WriteResult {
+    if res.kept_original {
+        WriteResult::kept(0)
     } else {
-        0
+        res
     }
 }
 
-pub fn generate_avif(img: &DynamicImage, path: &Path) -> u64 {
-    let avif_path = path.with_extension("avif");
-    let rgba = img.to_rgba8();
-    let (width, height) = img.dimensions();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
 
-    let src_img = imgref::Img::new(rgba.as_raw().as_rgba(), width as usize, height as usize);
+    // Each pixel stores its own coordinates, so any misplaced pixel is visible
+    fn coordinate_image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([x as u8, y as u8, 0])
+        }))
+    }
 
-    let enc = ravif::Encoder::new()
-        .with_quality(65.0)
-        .with_speed(4)
-        .with_alpha_quality(70.0)
-        .encode_rgba(src_img);
+    #[test]
+    fn apply_orientation_undoes_all_exif_orientations() {
+        let (w, h) = (3u32, 2u32);
+        // Stored pixel shown at display position (x, y), per the EXIF specification
+        let stored_at = |orientation: u16, x: u32, y: u32| match orientation {
+            1 => (x, y),
+            2 => (w - 1 - x, y),
+            3 => (w - 1 - x, h - 1 - y),
+            4 => (x, h - 1 - y),
+            5 => (y, x),
+            6 => (y, h - 1 - x),
+            7 => (w - 1 - y, h - 1 - x),
+            8 => (w - 1 - y, x),
+            _ => unreachable!(),
+        };
 
-    match enc {
-        Ok(encoded_image) => {
-            if fs::write(&avif_path, &encoded_image.avif_file).is_ok() {
-                encoded_image.avif_file.len() as u64
-            } else {
-                0
+        for orientation in 1..=8 {
+            let out = apply_orientation(coordinate_image(w, h), orientation).to_rgb8();
+            let expected = if orientation >= 5 { (h, w) } else { (w, h) };
+            assert_eq!(out.dimensions(), expected, "orientation {}", orientation);
+
+            for (x, y, pixel) in out.enumerate_pixels() {
+                let (sx, sy) = stored_at(orientation, x, y);
+                assert_eq!(
+                    (pixel[0] as u32, pixel[1] as u32),
+                    (sx, sy),
+                    "orientation {} at ({}, {})",
+                    orientation,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    fn resized(max_width: Option<u32>, max_height: Option<u32>, fit: FitMode) -> (u32, u32) {
+        let resize = ResizeConfig {
+            max_width,
+            max_height,
+            fit,
+            filter: ResizeFilter::Triangle,
+        };
+        resize_image(coordinate_image(600, 400), &resize).dimensions()
+    }
+
+    #[test]
+    fn resize_with_one_side_keeps_aspect_ratio_in_every_fit_mode() {
+        for fit in [FitMode::Contain, FitMode::Cover, FitMode::Fill] {
+            assert_eq!(resized(Some(120), None, fit), (120, 80), "{:?}", fit);
+            assert_eq!(resized(None, Some(100), fit), (150, 100), "{:?}", fit);
+        }
+    }
+
+    #[test]
+    fn resize_with_a_box_follows_the_fit_mode() {
+        assert_eq!(resized(Some(120), Some(120), FitMode::Contain), (120, 80));
+        assert_eq!(resized(Some(120), Some(120), FitMode::Cover), (120, 120));
+        assert_eq!(resized(Some(120), Some(60), FitMode::Fill), (120, 60));
+    }
+
+    #[test]
+    fn resize_never_upscales() {
+        for fit in [FitMode::Contain, FitMode::Cover, FitMode::Fill] {
+            assert_eq!(resized(Some(1200), None, fit), (600, 400), "{:?}", fit);
+            assert_eq!(resized(Some(1200), Some(900), fit), (600, 400), "{:?}", fit);
+        }
+    }
+
+    #[test]
+    fn apply_orientation_ignores_invalid_values() {
+        for orientation in [0, 9, u16::MAX] {
+            let out = apply_orientation(coordinate_image(3, 2), orientation).to_rgb8();
+            assert_eq!(out, coordinate_image(3, 2).to_rgb8());
+        }
+    }
+
+    // Frames of 4x4 palette indices, each frame a different solid index
+    fn gif_with(delays: &[u16], repeat: Option<gif::Repeat>) -> Vec<u8> {
+        let palette = [0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let mut out = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut out, 4, 4, &palette).unwrap();
+            if let Some(repeat) = repeat {
+                encoder.set_repeat(repeat).unwrap();
+            }
+            for (i, &delay) in delays.iter().enumerate() {
+                let mut frame = gif::Frame::from_indexed_pixels(4, 4, vec![i as u8 % 4; 16], None);
+                frame.delay = delay;
+                frame.interlaced = true;
+                encoder.write_frame(&frame).unwrap();
             }
         }
-        Err(e) => {
-            eprintln!("AVIF Error for {:?}: {}", path, e);
-            0
+        out
+    }
+
+    // Delays and pixels of every frame, and the repeat setting
+    fn read_gif(data: &[u8]) -> (Vec<(u16, Vec<u8>)>, gif::Repeat) {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(data).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        (frames, decoder.repeat())
+    }
+
+    #[test]
+    fn optimize_gif_keeps_frames_delays_and_loop_count() {
+        let source = gif_with(&[10, 25, 50], Some(gif::Repeat::Finite(2)));
+        let optimized = optimize_gif(&source).unwrap();
+
+        let (before, _) = read_gif(&source);
+        let (after, repeat) = read_gif(&optimized);
+        assert_eq!(after, before);
+        assert_eq!(after.iter().map(|(d, _)| *d as u32).sum::<u32>(), 85);
+        assert_eq!(repeat, gif::Repeat::Finite(2));
+        assert_eq!(gif_loop_count(&optimized), Ok(3));
+    }
+
+    #[test]
+    fn optimize_gif_keeps_an_infinite_loop() {
+        let optimized = optimize_gif(&gif_with(&[10, 10], Some(gif::Repeat::Infinite))).unwrap();
+        assert_eq!(read_gif(&optimized).1, gif::Repeat::Infinite);
+        assert_eq!(gif_loop_count(&optimized), Ok(0));
+    }
+
+    #[test]
+    fn gif_without_loop_extension_still_plays_once() {
+        let optimized = optimize_gif(&gif_with(&[10, 20], None)).unwrap();
+
+        assert_eq!(read_gif(&optimized).1, gif::Repeat::Finite(0));
+        // The encoder writes no NETSCAPE block for a single play
+        assert!(!optimized.windows(11).any(|w| w == b"NETSCAPE2.0"));
+        assert_eq!(gif_loop_count(&optimized), Ok(1));
+    }
+
+    #[test]
+    fn animated_webp_keeps_gif_timing_and_loop_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("anim.gif");
+        fs::write(&path, gif_with(&[10, 25, 50], Some(gif::Repeat::Finite(2)))).unwrap();
+
+        let anim = decode_gif_animation(&path).unwrap().unwrap();
+        let delays: Vec<u32> = anim.frames.iter().map(|(_, d)| *d).collect();
+        assert_eq!(delays, [100, 250, 500]);
+        assert_eq!(anim.loop_count, 3);
+
+        let settings = WebpSettings {
+            mode: WebpMode::Lossless,
+            ..Default::default()
+        };
+        let data = encode_animated_webp(&anim, 80, &settings).unwrap();
+
+        // ANMF payloads hold the frame duration as 24 bits at byte 12
+        let mut durations = Vec::new();
+        let mut loops = None;
+        let mut pos = 12;
+        while pos + 8 <= data.len() {
+            let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let payload = &data[pos + 8..pos + 8 + len];
+            match &data[pos..pos + 4] {
+                b"ANIM" => loops = Some(u16::from_le_bytes([payload[4], payload[5]])),
+                b"ANMF" => durations.push(u32::from_le_bytes([
+                    payload[12],
+                    payload[13],
+                    payload[14],
+                    0,
+                ])),
+                _ => {}
+            }
+            pos += 8 + len + (len & 1);
         }
+        assert_eq!(durations, [100, 250, 500]);
+        assert_eq!(loops, Some(3));
+        assert_eq!(webp::AnimDecoder::new(&data).decode().unwrap().len(), 3);
     }
 }
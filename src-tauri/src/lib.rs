@@ -1,5 +1,36 @@
+pub mod backup;
+#[cfg(feature = "gui")]
+mod commands;
+pub mod image_ops;
+pub mod manifest;
+pub mod metadata;
+pub mod optimizer;
+pub mod progress;
+pub mod quality;
+pub mod svg;
+pub mod tools;
+pub mod types;
+
+#[cfg(feature = "gui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    use moka::future::Cache;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use commands::{
+        cancel_optimization, check_tools, generate_thumbnail, get_last_result,
+        get_processing_state, restore_files, run_optimization, scan_dropped_paths, undo_last_run,
+    };
+    use image_ops::ImageCache;
+    use types::AppState;
+
+    let cache = Cache::builder()
+        .max_capacity(500)
+        .time_to_idle(Duration::from_secs(30 * 60))
+        .build();
+
     tauri::Builder::default()
         .setup(|app| {
             if cfg!(debug_assertions) {
@@ -11,6 +42,27 @@ pub fn run() {
             }
             Ok(())
         })
+        .plugin(tauri_plugin_window_state::Builder::default().build())
+        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_dialog::init())
+        .manage(AppState {
+            is_processing: Mutex::new(false),
+            should_cancel: Arc::new(AtomicBool::new(false)),
+            last_result: Mutex::new(None),
+        })
+        .manage(ImageCache(cache))
+        .invoke_handler(tauri::generate_handler![
+            run_optimization,
+            cancel_optimization,
+            generate_thumbnail,
+            get_processing_state,
+            get_last_result,
+            scan_dropped_paths,
+            check_tools,
+            undo_last_run,
+            restore_files
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::image_ops::write_atomic;
+use crate::types::OptimizeConfig;
+
+#[derive(Serialize, Deserialize, Default)]
+struct ManifestFile {
+    entries: BTreeSet<String>,
+    #[serde(default)]
+    outputs: BTreeSet<String>,
+}
+
+// Remembers which file contents were already processed with which settings,
+// and which files a run produced. Keys are content hashes, so renamed or moved
+// files are still recognized.
+pub struct Manifest {
+    path: PathBuf,
+    settings: String,
+    file: Mutex<ManifestFile>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path, config: &OptimizeConfig) -> Result<Self, String> {
+        // A missing or unreadable manifest only means everything gets processed again
+        let file = fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<ManifestFile>(&data).ok())
+            .unwrap_or_default();
+
+        Ok(Manifest {
+            path: path.to_path_buf(),
+            settings: settings_hash(config)?,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn contains(&self, content_hash: &str) -> bool {
+        let key = self.key(content_hash);
+        self.lock().entries.contains(&key)
+    }
+
+    pub fn record(&self, content_hash: &str) {
+        let key = self.key(content_hash);
+        self.lock().entries.insert(key);
+    }
+
+    pub fn is_output(&self, content_hash: &str) -> bool {
+        self.lock().outputs.contains(content_hash)
+    }
+
+    pub fn record_output(&self, content_hash: &str) {
+        self.lock().outputs.insert(content_hash.to_string());
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let data = serde_json::to_vec(&*self.lock()).map_err(|e| e.to_string())?;
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create manifest folder: {}", e))?;
+        }
+        write_atomic(&self.path, &data)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ManifestFile> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn key(&self, content_hash: &str) -> String {
+        format!("{}:{}", content_hash, self.settings)
+    }
+}
+
+// Restored originals have to be processed again, whatever settings they were
+// processed with before
+pub fn forget(path: &Path, content_hashes: &[String]) -> Result<(), String> {
+    let Some(mut file) = fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<ManifestFile>(&data).ok())
+    else {
+        return Ok(());
+    };
+
+    file.entries.retain(|key| {
+        let hash = key.split_once(':').map_or(key.as_str(), |(hash, _)| hash);
+        !content_hashes.iter().any(|h| h == hash)
+    });
+    let data = serde_json::to_vec(&file).map_err(|e| e.to_string())?;
+    write_atomic(path, &data)
+}
+
+pub fn hash_file(path: &Path) -> Result<String, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
+    Ok(blake3::hash(&data).to_hex().to_string())
+}
+
+// Only fields that change the produced files take part in the hash
+fn settings_hash(config: &OptimizeConfig) -> Result<String, String> {
+    let mut settings = config.clone();
+    settings.tasks.clear();
+    settings.tool_timeout_secs = 0;
+    settings.backup = false;
+    settings.backup_dir = None;
+    settings.backup_keep_runs = 0;
+    settings.dry_run = false;
+    settings.dry_run_sample = None;
+    settings.incremental = false;
+    settings.manifest_path = None;
+
+    let data = serde_json::to_vec(&settings).map_err(|e| e.to_string())?;
+    Ok(blake3::hash(&data).to_hex().to_string())
+}
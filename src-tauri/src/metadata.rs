@@ -0,0 +1,774 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::types::MetadataPolicy;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_ARTIST: u16 = 0x013B;
+const TAG_COPYRIGHT: u16 = 0x8298;
+
+enum ExifValue {
+    Ascii(String),
+    Short(u16),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Metadata {
+    pub icc: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    pub text: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.icc.is_none() && self.exif.is_none() && self.xmp.is_none() && self.text.is_empty()
+    }
+
+    pub fn filter(self, policy: MetadataPolicy) -> Metadata {
+        match policy {
+            MetadataPolicy::StripAll => Metadata::default(),
+            MetadataPolicy::KeepIcc => Metadata {
+                icc: self.icc,
+                ..Default::default()
+            },
+            MetadataPolicy::KeepCopyright => {
+                let mut tags = self.exif.as_deref().map(copyright_tags).unwrap_or_default();
+                for (keyword, value) in &self.text {
+                    let tag = match keyword.as_str() {
+                        "Author" => TAG_ARTIST,
+                        "Copyright" => TAG_COPYRIGHT,
+                        _ => continue,
+                    };
+                    if !tags.iter().any(|(t, _)| *t == tag) {
+                        tags.push((tag, ExifValue::Ascii(value.clone())));
+                    }
+                }
+
+                Metadata {
+                    icc: self.icc,
+                    exif: (!tags.is_empty()).then(|| build_exif(tags)),
+                    xmp: None,
+                    text: self
+                        .text
+                        .into_iter()
+                        .filter(|(k, _)| k == "Author" || k == "Copyright")
+                        .collect(),
+                }
+            }
+            MetadataPolicy::KeepAll => self,
+        }
+    }
+
+    pub fn orientation(&self) -> u16 {
+        self.exif
+            .as_deref()
+            .and_then(|exif| {
+                let (le, entry) = find_ifd0_entry(exif, TAG_ORIENTATION)?;
+                read_u16(exif, entry + 8, le)
+            })
+            .filter(|o| (1..=8).contains(o))
+            .unwrap_or(1)
+    }
+
+    pub fn set_orientation(&mut self, value: u16) {
+        if let Some(exif) = self.exif.as_mut() {
+            if let Some((le, entry)) = find_ifd0_entry(exif, TAG_ORIENTATION) {
+                if let Some(slot) = exif.get_mut(entry + 8..entry + 10) {
+                    let bytes = if le { value.to_le_bytes() } else { value.to_be_bytes() };
+                    slot.copy_from_slice(&bytes);
+                }
+                return;
+            }
+        }
+
+        if value == 1 {
+            return;
+        }
+
+        // Only our own minimal EXIF (copyright tags) can lack the tag here
+        let mut tags = self.exif.as_deref().map(copyright_tags).unwrap_or_default();
+        tags.push((TAG_ORIENTATION, ExifValue::Short(value)));
+        self.exif = Some(build_exif(tags));
+    }
+}
+
+pub fn read_metadata(path: &Path) -> Metadata {
+    let Ok(data) = fs::read(path) else {
+        return Metadata::default();
+    };
+
+    if data.starts_with(&[0xFF, 0xD8]) {
+        read_jpeg_metadata(&data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        read_png_metadata(&data)
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        read_webp_metadata(&data)
+    } else {
+        Metadata::default()
+    }
+}
+
+fn read_jpeg_metadata(data: &[u8]) -> Metadata {
+    let mut meta = Metadata::default();
+    let mut icc_chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut pos = 2;
+
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            break;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xD8 || marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            break;
+        }
+        let payload = &data[pos + 4..end];
+
+        match marker {
+            0xE1 if payload.starts_with(EXIF_HEADER) && meta.exif.is_none() => {
+                meta.exif = Some(payload[EXIF_HEADER.len()..].to_vec());
+            }
+            0xE1 if payload.starts_with(XMP_HEADER) && meta.xmp.is_none() => {
+                meta.xmp = Some(payload[XMP_HEADER.len()..].to_vec());
+            }
+            0xE2 if payload.starts_with(ICC_HEADER) && payload.len() > ICC_HEADER.len() + 2 => {
+                let seq = payload[ICC_HEADER.len()];
+                icc_chunks.push((seq, &payload[ICC_HEADER.len() + 2..]));
+            }
+            _ => {}
+        }
+
+        pos = end;
+    }
+
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(seq, _)| *seq);
+        meta.icc = Some(icc_chunks.iter().flat_map(|(_, c)| c.iter().copied()).collect());
+    }
+
+    meta
+}
+
+fn read_png_metadata(data: &[u8]) -> Metadata {
+    let mut meta = Metadata::default();
+
+    for (kind, payload) in png_chunks(data) {
+        match kind {
+            b"iCCP" => {
+                let Some(nul) = payload.iter().position(|&b| b == 0) else {
+                    continue;
+                };
+                let mut profile = Vec::new();
+                let compressed = payload.get(nul + 2..).unwrap_or_default();
+                if ZlibDecoder::new(compressed)
+                    .read_to_end(&mut profile)
+                    .is_ok()
+                {
+                    meta.icc = Some(profile);
+                }
+            }
+            b"eXIf" => meta.exif = Some(payload.to_vec()),
+            b"tEXt" => {
+                if let Some(nul) = payload.iter().position(|&b| b == 0) {
+                    let keyword = String::from_utf8_lossy(&payload[..nul]).to_string();
+                    let value = payload[nul + 1..].iter().map(|&b| b as char).collect();
+                    meta.text.push((keyword, value));
+                }
+            }
+            b"iTXt" => {
+                // keyword\0 flag method lang\0 translated\0 text; only uncompressed XMP is kept
+                let Some(nul) = payload.iter().position(|&b| b == 0) else {
+                    continue;
+                };
+                if &payload[..nul] != XMP_KEYWORD.as_bytes() || payload.get(nul + 1) != Some(&0) {
+                    continue;
+                }
+                let rest = payload.get(nul + 3..).unwrap_or_default();
+                let mut parts = rest.splitn(3, |&b| b == 0);
+                if let (Some(_), Some(_), Some(text)) = (parts.next(), parts.next(), parts.next()) {
+                    meta.xmp = Some(text.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    meta
+}
+
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = PNG_SIGNATURE.len();
+    std::iter::from_fn(move || {
+        if pos + 12 > data.len() {
+            return None;
+        }
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let payload = data.get(pos + 8..pos + 8 + len)?;
+        pos += 12 + len;
+        Some((kind, payload))
+    })
+}
+
+fn read_webp_metadata(data: &[u8]) -> Metadata {
+    let mut meta = Metadata::default();
+
+    for (kind, payload) in riff_chunks(data) {
+        match kind {
+            b"ICCP" => meta.icc = Some(payload.to_vec()),
+            // Some writers keep the JPEG APP1 prefix
+            b"EXIF" => {
+                let exif = payload.strip_prefix(EXIF_HEADER).unwrap_or(payload);
+                meta.exif = Some(exif.to_vec());
+            }
+            b"XMP " => meta.xmp = Some(payload.to_vec()),
+            _ => {}
+        }
+    }
+
+    meta
+}
+
+fn riff_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 12;
+    std::iter::from_fn(move || {
+        if pos + 8 > data.len() {
+            return None;
+        }
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().ok()?) as usize;
+        let kind = &data[pos..pos + 4];
+        let payload = data.get(pos + 8..pos + 8 + len)?;
+        pos += 8 + len + (len & 1);
+        Some((kind, payload))
+    })
+}
+
+fn read_u16(data: &[u8], pos: usize, le: bool) -> Option<u16> {
+    let b: [u8; 2] = data.get(pos..pos + 2)?.try_into().ok()?;
+    Some(if le {
+        u16::from_le_bytes(b)
+    } else {
+        u16::from_be_bytes(b)
+    })
+}
+
+fn read_u32(data: &[u8], pos: usize, le: bool) -> Option<u32> {
+    let b: [u8; 4] = data.get(pos..pos + 4)?.try_into().ok()?;
+    Some(if le {
+        u32::from_le_bytes(b)
+    } else {
+        u32::from_be_bytes(b)
+    })
+}
+
+// Returns (little endian, offset of the IFD0 entry for `tag`)
+fn find_ifd0_entry(exif: &[u8], tag: u16) -> Option<(bool, usize)> {
+    let le = match exif.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let ifd = read_u32(exif, 4, le)? as usize;
+    let count = read_u16(exif, ifd, le)? as usize;
+
+    (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(exif, entry, le) == Some(tag))
+        .map(|entry| (le, entry))
+}
+
+fn read_ascii_tag(exif: &[u8], tag: u16) -> Option<String> {
+    let (le, entry) = find_ifd0_entry(exif, tag)?;
+    if read_u16(exif, entry + 2, le)? != 2 {
+        return None;
+    }
+    let count = read_u32(exif, entry + 4, le)? as usize;
+    let start = if count <= 4 {
+        entry + 8
+    } else {
+        read_u32(exif, entry + 8, le)? as usize
+    };
+    let raw = exif.get(start..start + count)?;
+    let value = String::from_utf8_lossy(raw)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn copyright_tags(exif: &[u8]) -> Vec<(u16, ExifValue)> {
+    [TAG_ARTIST, TAG_COPYRIGHT]
+        .into_iter()
+        .filter_map(|tag| read_ascii_tag(exif, tag).map(|v| (tag, ExifValue::Ascii(v))))
+        .collect()
+}
+
+fn build_exif(mut tags: Vec<(u16, ExifValue)>) -> Vec<u8> {
+    tags.sort_by_key(|(t, _)| *t);
+
+    let mut out = b"II*\0".to_vec();
+    out.extend_from_slice(&8u32.to_le_bytes());
+    out.extend_from_slice(&(tags.len() as u16).to_le_bytes());
+
+    let mut data_offset = 8 + 2 + tags.len() * 12 + 4;
+    let mut values = Vec::new();
+
+    for (tag, value) in &tags {
+        let (kind, mut bytes, count) = match value {
+            ExifValue::Ascii(text) => {
+                let mut bytes = text.as_bytes().to_vec();
+                bytes.push(0);
+                let count = bytes.len();
+                (2u16, bytes, count)
+            }
+            ExifValue::Short(v) => (3u16, v.to_le_bytes().to_vec(), 1),
+        };
+
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&(count as u32).to_le_bytes());
+        if bytes.len() <= 4 {
+            bytes.resize(4, 0);
+            out.extend_from_slice(&bytes);
+        } else {
+            out.extend_from_slice(&(data_offset as u32).to_le_bytes());
+            data_offset += bytes.len();
+            values.extend_from_slice(&bytes);
+        }
+    }
+
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&values);
+    out
+}
+
+pub fn write_jpeg_metadata<W>(comp: &mut mozjpeg::compress::CompressStarted<W>, meta: &Metadata) {
+    const MAX_MARKER: usize = 65533;
+
+    if let Some(exif) = &meta.exif {
+        if exif.len() + EXIF_HEADER.len() <= MAX_MARKER {
+            comp.write_marker(mozjpeg::Marker::APP(1), &[EXIF_HEADER, exif].concat());
+        }
+    }
+    if let Some(xmp) = &meta.xmp {
+        if xmp.len() + XMP_HEADER.len() <= MAX_MARKER {
+            comp.write_marker(mozjpeg::Marker::APP(1), &[XMP_HEADER, xmp].concat());
+        }
+    }
+    if let Some(icc) = meta.icc.as_deref().filter(|icc| !icc.is_empty()) {
+        comp.write_icc_profile(icc);
+    }
+}
+
+fn png_chunk(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(payload);
+
+    let mut out = Vec::with_capacity(payload.len() + 12);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+    out
+}
+
+pub fn embed_png_metadata(data: &[u8], meta: &Metadata) -> Vec<u8> {
+    if meta.is_empty() || !data.starts_with(PNG_SIGNATURE) {
+        return data.to_vec();
+    }
+
+    let mut extra = Vec::new();
+
+    if let Some(icc) = &meta.icc {
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::best());
+        if enc.write_all(icc).is_ok() {
+            if let Ok(compressed) = enc.finish() {
+                extra.extend(png_chunk(
+                    b"iCCP",
+                    &[b"ICC Profile\0\0", &compressed[..]].concat(),
+                ));
+            }
+        }
+    }
+    if let Some(exif) = &meta.exif {
+        extra.extend(png_chunk(b"eXIf", exif));
+    }
+    if let Some(xmp) = &meta.xmp {
+        let header = format!("{}\0\0\0\0\0", XMP_KEYWORD);
+        extra.extend(png_chunk(b"iTXt", &[header.as_bytes(), xmp].concat()));
+    }
+    for (keyword, value) in &meta.text {
+        let latin1: Vec<u8> = value.chars().map(|c| c as u32 as u8).collect();
+        extra.extend(png_chunk(
+            b"tEXt",
+            &[keyword.as_bytes(), b"\0", &latin1].concat(),
+        ));
+    }
+
+    // signature + IHDR (length, type, 13 bytes of data, crc)
+    let ihdr_end = PNG_SIGNATURE.len() + 8 + 13 + 4;
+    if data.len() < ihdr_end {
+        return data.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(data.len() + extra.len());
+    out.extend_from_slice(&data[..ihdr_end]);
+    out.extend_from_slice(&extra);
+    out.extend_from_slice(&data[ihdr_end..]);
+    out
+}
+
+fn riff_chunk(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 9);
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+pub fn embed_webp_metadata(data: &[u8], width: u32, height: u32, meta: &Metadata) -> Vec<u8> {
+    if meta.is_empty() || data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return data.to_vec();
+    }
+
+    let mut flags = 0u8;
+    let mut image_chunks = Vec::new();
+    let mut pos = 12;
+
+    while pos + 8 <= data.len() {
+        let kind = &data[pos..pos + 4];
+        let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let end = (pos + 8 + len + (len & 1)).min(data.len());
+        let payload = &data[pos + 8..(pos + 8 + len).min(data.len())];
+
+        match kind {
+            b"VP8X" => flags |= payload.first().copied().unwrap_or(0),
+            b"ICCP" | b"EXIF" | b"XMP " => {}
+            _ => {
+                if kind == b"ALPH" {
+                    flags |= 0x10;
+                }
+                // VP8L header: signature byte, then 14+14 bits size and the alpha bit
+                if kind == b"VP8L" && payload.len() >= 5 {
+                    let bits = u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]);
+                    if (bits >> 28) & 1 == 1 {
+                        flags |= 0x10;
+                    }
+                }
+                image_chunks.extend_from_slice(&data[pos..end]);
+            }
+        }
+
+        pos = end;
+    }
+
+    if meta.icc.is_some() {
+        flags |= 0x20;
+    }
+    if meta.exif.is_some() {
+        flags |= 0x08;
+    }
+    if meta.xmp.is_some() {
+        flags |= 0x04;
+    }
+
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut body = b"WEBP".to_vec();
+    body.extend(riff_chunk(b"VP8X", &vp8x));
+    if let Some(icc) = &meta.icc {
+        body.extend(riff_chunk(b"ICCP", icc));
+    }
+    body.extend_from_slice(&image_chunks);
+    if let Some(exif) = &meta.exif {
+        body.extend(riff_chunk(b"EXIF", exif));
+    }
+    if let Some(xmp) = &meta.xmp {
+        body.extend(riff_chunk(b"XMP ", xmp));
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend(body);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_ops::encode_png;
+    use image::{DynamicImage, RgbaImage};
+
+    const POLICIES: [MetadataPolicy; 4] = [
+        MetadataPolicy::StripAll,
+        MetadataPolicy::KeepIcc,
+        MetadataPolicy::KeepCopyright,
+        MetadataPolicy::KeepAll,
+    ];
+
+    fn sample() -> Metadata {
+        Metadata {
+            icc: Some(b"not a real profile, only carried through".to_vec()),
+            exif: Some(build_exif(vec![
+                (TAG_ARTIST, ExifValue::Ascii("Jane Doe".into())),
+                (TAG_COPYRIGHT, ExifValue::Ascii("CC BY 4.0".into())),
+                (TAG_ORIENTATION, ExifValue::Short(6)),
+            ])),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+            text: vec![
+                ("Author".into(), "Jane Doe".into()),
+                ("Comment".into(), "draft".into()),
+            ],
+        }
+    }
+
+    fn rgba(width: u32, height: u32, alpha: u8) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 16) as u8, (y * 16) as u8, 128, alpha])
+        })
+    }
+
+    fn webp_flags(data: &[u8]) -> u8 {
+        riff_chunks(data)
+            .find(|(kind, _)| *kind == b"VP8X")
+            .and_then(|(_, payload)| payload.first().copied())
+            .unwrap()
+    }
+
+    #[test]
+    fn filter_keeps_what_each_policy_allows() {
+        let stripped = sample().filter(MetadataPolicy::StripAll);
+        assert!(stripped.is_empty());
+
+        let icc = sample().filter(MetadataPolicy::KeepIcc);
+        assert_eq!(icc.icc, sample().icc);
+        assert!(icc.exif.is_none() && icc.xmp.is_none() && icc.text.is_empty());
+
+        let copyright = sample().filter(MetadataPolicy::KeepCopyright);
+        let exif = copyright.exif.as_deref().unwrap();
+        assert_eq!(copyright.icc, sample().icc);
+        assert_eq!(
+            read_ascii_tag(exif, TAG_ARTIST).as_deref(),
+            Some("Jane Doe")
+        );
+        assert_eq!(
+            read_ascii_tag(exif, TAG_COPYRIGHT).as_deref(),
+            Some("CC BY 4.0")
+        );
+        assert!(find_ifd0_entry(exif, TAG_ORIENTATION).is_none());
+        assert!(copyright.xmp.is_none());
+        assert_eq!(copyright.text, vec![("Author".into(), "Jane Doe".into())]);
+
+        let all = sample().filter(MetadataPolicy::KeepAll);
+        assert_eq!(all.exif, sample().exif);
+        assert_eq!(all.xmp, sample().xmp);
+        assert_eq!(all.text, sample().text);
+    }
+
+    #[test]
+    fn copyright_policy_turns_png_text_into_exif() {
+        let meta = Metadata {
+            text: vec![("Copyright".into(), "CC BY 4.0".into())],
+            ..Default::default()
+        };
+        let exif = meta.filter(MetadataPolicy::KeepCopyright).exif.unwrap();
+        assert_eq!(
+            read_ascii_tag(&exif, TAG_COPYRIGHT).as_deref(),
+            Some("CC BY 4.0")
+        );
+    }
+
+    #[test]
+    fn build_exif_writes_a_well_formed_ifd() {
+        let exif = sample().exif.unwrap();
+        assert_eq!(&exif[..8], b"II*\0\x08\0\0\0");
+
+        let count = read_u16(&exif, 8, true).unwrap() as usize;
+        assert_eq!(count, 3);
+        let mut last_tag = 0;
+        for i in 0..count {
+            let entry = 10 + i * 12;
+            let tag = read_u16(&exif, entry, true).unwrap();
+            // ASCII values are one byte per count, SHORT values two
+            let width = match read_u16(&exif, entry + 2, true).unwrap() {
+                2 => 1,
+                3 => 2,
+                kind => panic!("unexpected type {}", kind),
+            };
+            let len = read_u32(&exif, entry + 4, true).unwrap() as usize * width;
+            if len > 4 {
+                let offset = read_u32(&exif, entry + 8, true).unwrap() as usize;
+                assert!(offset + len <= exif.len());
+            }
+            // TIFF readers expect the entries sorted by tag
+            assert!(tag > last_tag);
+            last_tag = tag;
+        }
+        assert_eq!(read_u32(&exif, 10 + count * 12, true), Some(0));
+
+        let meta = Metadata {
+            exif: Some(exif),
+            ..Default::default()
+        };
+        assert_eq!(meta.orientation(), 6);
+    }
+
+    #[test]
+    fn png_metadata_round_trips_through_the_png_decoder() {
+        let png = encode_png(&DynamicImage::ImageRgba8(rgba(8, 8, 255))).unwrap();
+
+        for policy in POLICIES {
+            let meta = sample().filter(policy);
+            let data = embed_png_metadata(&png, &meta);
+            assert!(image::load_from_memory(&data).is_ok());
+
+            let reader = png::Decoder::new(data.as_slice()).read_info().unwrap();
+            let info = reader.info();
+            assert_eq!(info.icc_profile.as_deref(), meta.icc.as_deref());
+            // The decoder checks the eXIf CRC but does not expose its payload
+            let exif = png_chunks(&data)
+                .find(|(kind, _)| *kind == b"eXIf")
+                .map(|(_, payload)| payload);
+            assert_eq!(exif, meta.exif.as_deref());
+
+            let xmp = info
+                .utf8_text
+                .iter()
+                .find(|chunk| chunk.keyword == XMP_KEYWORD)
+                .map(|chunk| chunk.get_text().unwrap().into_bytes());
+            assert_eq!(xmp, meta.xmp);
+
+            let text: Vec<(String, String)> = info
+                .uncompressed_latin1_text
+                .iter()
+                .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+                .collect();
+            assert_eq!(text, meta.text);
+
+            let read = read_png_metadata(&data);
+            assert_eq!(
+                (read.icc, read.exif, read.xmp),
+                (meta.icc, meta.exif, meta.xmp)
+            );
+        }
+    }
+
+    #[test]
+    fn webp_metadata_round_trips_with_matching_vp8x_flags() {
+        let opaque = rgba(8, 8, 255);
+        let translucent = rgba(8, 8, 100);
+        let encoded = [
+            (webp::Encoder::from_rgba(&opaque, 8, 8).encode(80.0), false),
+            (
+                webp::Encoder::from_rgba(&translucent, 8, 8).encode(80.0),
+                true,
+            ),
+            (
+                webp::Encoder::from_rgba(&translucent, 8, 8).encode_lossless(),
+                true,
+            ),
+        ];
+
+        for (webp, has_alpha) in &encoded {
+            for policy in POLICIES {
+                let meta = sample().filter(policy);
+                let data = embed_webp_metadata(webp, 8, 8, &meta);
+
+                let features = webp::BitstreamFeatures::new(&data).unwrap();
+                assert_eq!(features.has_alpha(), *has_alpha);
+                assert!(webp::Decoder::new(&data).decode().is_some());
+
+                if meta.is_empty() {
+                    assert_eq!(&data, &**webp);
+                    continue;
+                }
+                let flags = webp_flags(&data);
+                assert_eq!(flags & 0x20 != 0, meta.icc.is_some());
+                assert_eq!(flags & 0x10 != 0, *has_alpha);
+                assert_eq!(flags & 0x08 != 0, meta.exif.is_some());
+                assert_eq!(flags & 0x04 != 0, meta.xmp.is_some());
+
+                let read = read_webp_metadata(&data);
+                assert_eq!(
+                    (read.icc, read.exif, read.xmp),
+                    (meta.icc, meta.exif, meta.xmp)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn animated_webp_keeps_its_animation_flag_and_frames() {
+        let frames = [rgba(8, 8, 255), rgba(8, 8, 100)];
+        let config = webp::WebPConfig::new().unwrap();
+        let mut encoder = webp::AnimEncoder::new(8, 8, &config);
+        for (i, frame) in frames.iter().enumerate() {
+            encoder.add_frame(webp::AnimFrame::from_rgba(frame, 8, 8, i as i32 * 100));
+        }
+        let webp = encoder.encode();
+
+        let meta = sample().filter(MetadataPolicy::KeepAll);
+        let data = embed_webp_metadata(&webp, 8, 8, &meta);
+
+        assert_eq!(webp_flags(&data) & 0x02, 0x02);
+        assert!(webp::BitstreamFeatures::new(&data).unwrap().has_animation());
+        assert_eq!(webp::AnimDecoder::new(&data).decode().unwrap().len(), 2);
+        assert_eq!(read_webp_metadata(&data).exif, meta.exif);
+    }
+
+    #[test]
+    fn orientation_round_trips_for_all_values() {
+        for orientation in 1..=8 {
+            let mut meta = Metadata::default();
+            meta.set_orientation(orientation);
+            assert_eq!(meta.orientation(), orientation);
+        }
+    }
+
+    #[test]
+    fn set_orientation_resets_an_existing_tag() {
+        let mut meta = Metadata::default();
+        meta.set_orientation(6);
+        let size = meta.exif.as_ref().map(Vec::len);
+
+        meta.set_orientation(1);
+        assert_eq!(meta.orientation(), 1);
+        // The tag is rewritten in place rather than appended
+        assert_eq!(meta.exif.as_ref().map(Vec::len), size);
+    }
+
+    #[test]
+    fn missing_orientation_reads_as_one() {
+        let mut meta = Metadata::default();
+        assert_eq!(meta.orientation(), 1);
+        meta.set_orientation(1);
+        assert!(meta.exif.is_none());
+    }
+}
@@ -1,120 +1,397 @@
+use image::{DynamicImage, GenericImageView};
 use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-use tauri::{Emitter, Window};
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
-use crate::image_ops::{generate_avif, generate_webp, process_jpg, process_png};
-use crate::tools::{get_png_tools, ToolPath};
-use crate::types::{FileStats, FinalResult, OptimizeConfig, ProgressPayload};
+use crate::backup::{prune_runs, BackupStore};
+use crate::image_ops::{
+    apply_orientation, decode_gif_animation, decode_image, encode_png, generate_animated_webp,
+    generate_avif, generate_jxl, generate_webp, is_animated_webp, looks_like_graphic, process_avif,
+    process_gif, process_jpg, process_png, process_svg, process_webp, resize_image,
+    resize_to_width, write_atomic, JxlSource, PngOptions, WriteResult,
+};
+use crate::manifest::{hash_file, Manifest};
+use crate::metadata::read_metadata;
+use crate::metadata::Metadata;
+use crate::progress::ProgressSink;
+use crate::quality::{validate_target, QualityTarget, SizeBudget};
+use crate::tools::{get_avif_decoder, get_jxl_encoder, get_png_tools, probe_tool};
+use crate::types::{
+    FileOutcome, FileStats, FinalResult, OptimizeConfig, OutputFile, PngBackend, ProgressPayload,
+    QualityChoice, WebpMode, WebpSettings,
+};
+
+const ALREADY_OPTIMIZED: &str = "Already optimized with these settings";
+const EARLIER_OUTPUT: &str = "Output of an earlier run";
+const NAME_TAKEN: &str = "Output name is already used by another file";
+
+// Formats that are not served on the web as they are, so they always get converted
+const CONVERTED_EXTS: [&str; 4] = ["tif", "tiff", "bmp", "tga"];
+// Sidecar formats; as sources their name can collide with another file's sidecar
+const SIDECAR_EXTS: [&str; 2] = ["webp", "avif"];
+
+pub struct FileJob {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    pub variants: Vec<(u32, PathBuf)>,
+    pub content_hash: Option<String>,
+    pub skip: Option<&'static str>,
+    // Sidecar formats whose file name belongs to another file of the run
+    pub blocked_sidecars: Vec<&'static str>,
+}
 
 pub fn perform_optimization(
-    window: &Window,
+    sink: &dyn ProgressSink,
     config: OptimizeConfig,
     should_cancel: Arc<AtomicBool>,
 ) -> Result<FinalResult, String> {
     let start_time = Instant::now();
 
+    if let Some(target) = config.target_ssim {
+        validate_target(target)?;
+    }
+    if config.max_bytes == Some(0) {
+        return Err("Max bytes must be greater than 0".to_string());
+    }
+    // cjxl runs once per file, JPEG transcodes and GIFs have no quality to search
+    if config.jxl && config.max_bytes.is_some() {
+        return Err("Max bytes cannot be combined with JPEG XL output".to_string());
+    }
+    config.webp_settings.validate()?;
+    config.avif_settings.validate()?;
+    config.jxl_settings.validate()?;
+    config.svg_settings.validate()?;
+    let png_settings = config
+        .png_settings
+        .clone()
+        .unwrap_or_else(|| config.png_preset.into());
+    png_settings.validate()?;
+    if config.backup && config.backup_keep_runs == 0 {
+        return Err("Backup retention must keep at least one run".to_string());
+    }
+    if config.dry_run_sample == Some(0) {
+        return Err("Sample size must be greater than 0".to_string());
+    }
+
     let (_tmp_dir, pq, oxi) =
         get_png_tools().map_err(|e| format!("Failed to setup tools: {}", e))?;
-    let _ = window.emit("status_update", "Preparing files...");
+    let png = PngOptions {
+        backend: config.png_backend,
+        pngquant: pq,
+        oxipng: oxi,
+        min_quality: config.png_min,
+        max_quality: config.png_max,
+        settings: png_settings,
+        timeout: (config.tool_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.tool_timeout_secs)),
+        cancel: should_cancel.clone(),
+    };
+    sink.status("Preparing files...");
 
-    let file_tasks = collect_file_tasks(&config)?;
+    let manifest = match (config.incremental, &config.manifest_path) {
+        (false, _) => None,
+        (true, Some(path)) => Some(Manifest::load(Path::new(path), &config)?),
+        (true, None) => return Err("Incremental mode is enabled but no manifest is set.".into()),
+    };
+
+    let file_tasks = collect_file_tasks(&config, manifest.as_ref())?;
+
+    // Only the sample is encoded; the rest of the folder is projected from it
+    let (file_tasks, projection) = if config.dry_run {
+        let all_files = file_tasks.len() as u64;
+        let all_bytes = file_tasks
+            .iter()
+            .map(|job| fs::metadata(&job.src).map(|m| m.len()).unwrap_or(0))
+            .sum();
+        (
+            sample_tasks(file_tasks, config.dry_run_sample),
+            Some((all_files, all_bytes)),
+        )
+    } else {
+        (file_tasks, None)
+    };
     let total_files_count = file_tasks.len() as u64;
 
-    let _ = window.emit(
-        "progress",
-        ProgressPayload {
-            total: total_files_count,
-            done: 0,
-            current_file: "Starting...".into(),
-        },
-    );
+    let has_png = file_tasks.iter().any(|job| {
+        job.dest
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+    });
+    if config.optimize_original && config.png_backend == PngBackend::External && has_png {
+        for (name, tool) in [("pngquant", &png.pngquant), ("oxipng", &png.oxipng)] {
+            let status = probe_tool(name, tool);
+            if !status.available {
+                return Err(format!(
+                    "{}. Install it or switch to the native PNG backend.",
+                    status
+                        .error
+                        .unwrap_or_else(|| format!("{} is not available", name))
+                ));
+            }
+        }
+    }
+
+    if config.jxl {
+        let status = probe_tool("cjxl", &get_jxl_encoder());
+        if !status.available {
+            return Err(format!(
+                "{}. Install libjxl or turn off JPEG XL output.",
+                status
+                    .error
+                    .unwrap_or_else(|| "cjxl is not available".to_string())
+            ));
+        }
+    }
+
+    // AVIF sources are decoded by avifdec, so without it every one of them would fail
+    let has_avif = file_tasks
+        .iter()
+        .any(|job| job.skip.is_none() && has_ext(&job.src, &["avif"]));
+    if has_avif {
+        let status = probe_tool("avifdec", &get_avif_decoder());
+        if !status.available {
+            return Err(format!(
+                "{}. Install libavif or leave AVIF files out of the selection.",
+                status
+                    .error
+                    .unwrap_or_else(|| "avifdec is not available".to_string())
+            ));
+        }
+    }
+
+    // Files the manifest skips are never rewritten, so they need no backup run
+    let overwrites = config.optimize_original
+        && file_tasks
+            .iter()
+            .any(|job| job.skip.is_none() && job.src == job.dest);
+    let backup = match (
+        config.backup && overwrites && !config.dry_run,
+        &config.backup_dir,
+    ) {
+        (false, _) => None,
+        (true, Some(dir)) => Some(BackupStore::create(Path::new(dir))?),
+        (true, None) => return Err("Backup is enabled but no backup folder is set.".into()),
+    };
+
+    sink.progress(ProgressPayload {
+        total: total_files_count,
+        done: 0,
+        current_file: "Starting...".into(),
+    });
 
     let done_counter = Arc::new(AtomicU64::new(0));
+    let ctx = RunContext {
+        config: &config,
+        png: &png,
+        backup: backup.as_ref(),
+        manifest: manifest.as_ref(),
+        should_cancel: &should_cancel,
+    };
 
     let results: Vec<FileStats> = file_tasks
         .par_iter()
-        .map(|(src, dest)| {
+        .map(|job| {
             if should_cancel.load(Ordering::Relaxed) {
-                return FileStats::default();
+                let mut stats = FileStats::skipped("Canceled");
+                stats.path = job.src.to_string_lossy().to_string();
+                return stats;
             }
 
-            process_single_file(
-                src,
-                dest,
-                &config,
-                &pq,
-                &oxi,
-                window,
-                &done_counter,
-                total_files_count,
-                &should_cancel,
-            )
+            process_single_file(job, &ctx, sink, &done_counter, total_files_count)
         })
         .collect();
 
+    if let Some(manifest) = manifest.filter(|_| !config.dry_run) {
+        manifest.save()?;
+    }
+
+    // Runs that cannot be removed now are retried after the next one
+    if let (Some(_), Some(dir)) = (&backup, &config.backup_dir) {
+        prune_runs(Path::new(dir), config.backup_keep_runs).ok();
+    }
+
     let is_canceled = should_cancel.load(Ordering::Relaxed);
-    let duration_total_wall = start_time.elapsed().as_secs_f64();
+    let processed_count = done_counter.load(Ordering::Relaxed);
 
+    let mut result = summarize(
+        results,
+        total_files_count,
+        processed_count,
+        is_canceled,
+        start_time.elapsed().as_secs_f64(),
+        backup.map(|b| b.id().to_string()),
+    );
+    if let Some((all_files, all_bytes)) = projection {
+        project(&mut result, all_files, all_bytes);
+    }
+
+    Ok(result)
+}
+
+fn sample_tasks(tasks: Vec<FileJob>, sample: Option<usize>) -> Vec<FileJob> {
+    let total = tasks.len();
+    let count = match sample {
+        Some(count) if count < total => count,
+        _ => return tasks,
+    };
+
+    // Evenly spaced picks keep the sample spread across the sorted folder tree
+    let picks: HashSet<usize> = (0..count).map(|i| i * total / count).collect();
+    tasks
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picks.contains(i))
+        .map(|(_, job)| job)
+        .collect()
+}
+
+// Scales sizes and times by the share of input bytes the sample covered.
+// File counts stay those of the sample.
+fn project(result: &mut FinalResult, all_files: u64, all_bytes: u64) {
+    result.is_estimate = true;
+    result.sampled_files = result.files.len() as u64;
+    result.total_files = all_files;
+
+    let sampled_bytes = result.total_size_original;
+    if sampled_bytes == 0 || sampled_bytes == all_bytes {
+        return;
+    }
+
+    let ratio = all_bytes as f64 / sampled_bytes as f64;
+    let scale = |value: u64| (value as f64 * ratio).round() as u64;
+
+    result.total_size_original = all_bytes;
+    result.total_size_saved = scale(result.total_size_saved);
+    result.total_size_optimized = scale(result.total_size_optimized);
+    result.total_size_webp = scale(result.total_size_webp);
+    result.total_size_avif = scale(result.total_size_avif);
+    result.total_size_jxl = scale(result.total_size_jxl);
+    result.total_size_variants = scale(result.total_size_variants);
+    result.duration_total *= ratio;
+    result.duration_opt *= ratio;
+    result.duration_webp *= ratio;
+    result.duration_avif *= ratio;
+    result.duration_jxl *= ratio;
+}
+
+fn summarize(
+    results: Vec<FileStats>,
+    total_files: u64,
+    processed_files: u64,
+    is_canceled: bool,
+    duration_total_wall: f64,
+    backup_run: Option<String>,
+) -> FinalResult {
     let mut total_saved = 0;
     let mut total_original = 0;
     let mut total_optimized = 0;
     let mut total_webp_size = 0;
     let mut total_avif_size = 0;
+    let mut total_jxl_size = 0;
+    let mut total_variants_size = 0;
+    let mut kept_original_files = 0;
+    let mut discarded_webp = 0;
+    let mut discarded_avif = 0;
+    let mut discarded_jxl = 0;
+    let mut skipped_files = 0;
+    let mut failed_files = 0;
 
     let mut sum_cpu_opt = 0.0;
     let mut sum_cpu_webp = 0.0;
     let mut sum_cpu_avif = 0.0;
+    let mut sum_cpu_jxl = 0.0;
 
-    for s in results {
+    for s in &results {
         total_saved += s.bytes_saved;
         total_original += s.original_size;
         total_optimized += s.optimized_size;
         total_webp_size += s.webp_size;
         total_avif_size += s.avif_size;
+        total_jxl_size += s.jxl_size;
+        total_variants_size += s.variants.iter().map(|v| v.size).sum::<u64>();
+        kept_original_files += s.kept_original as u64;
+        discarded_webp += s.webp_discarded as u64;
+        discarded_avif += s.avif_discarded as u64;
+        discarded_jxl += s.jxl_discarded as u64;
+
+        match s.outcome {
+            FileOutcome::Skipped(_) => skipped_files += 1,
+            FileOutcome::Failed(_) => failed_files += 1,
+            _ => {}
+        }
 
         sum_cpu_opt += s.duration_opt;
         sum_cpu_webp += s.duration_webp;
         sum_cpu_avif += s.duration_avif;
+        sum_cpu_jxl += s.duration_jxl;
     }
 
-    let total_cpu_time = sum_cpu_opt + sum_cpu_webp + sum_cpu_avif;
+    let total_cpu_time = sum_cpu_opt + sum_cpu_webp + sum_cpu_avif + sum_cpu_jxl;
     let factor = if total_cpu_time > 0.0001 {
         duration_total_wall / total_cpu_time
     } else {
         0.0
     };
 
-    let processed_count = done_counter.load(Ordering::Relaxed);
-
-    Ok(FinalResult {
-        total_files: total_files_count,
-        processed_files: processed_count,
+    FinalResult {
+        total_files,
+        processed_files,
         is_canceled,
         total_size_saved: total_saved,
         duration_total: duration_total_wall,
         duration_opt: sum_cpu_opt * factor,
         duration_webp: sum_cpu_webp * factor,
         duration_avif: sum_cpu_avif * factor,
+        duration_jxl: sum_cpu_jxl * factor,
         total_size_original: total_original,
         total_size_optimized: total_optimized,
         total_size_webp: total_webp_size,
         total_size_avif: total_avif_size,
-    })
+        total_size_jxl: total_jxl_size,
+        total_size_variants: total_variants_size,
+        kept_original_files,
+        discarded_webp,
+        discarded_avif,
+        discarded_jxl,
+        skipped_files,
+        failed_files,
+        backup_run,
+        is_estimate: false,
+        sampled_files: 0,
+        files: results,
+    }
 }
 
-fn collect_file_tasks(config: &OptimizeConfig) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+pub fn collect_file_tasks(
+    config: &OptimizeConfig,
+    manifest: Option<&Manifest>,
+) -> Result<Vec<FileJob>, String> {
     let mut tasks = Vec::new();
-    let supported_exts = ["png", "jpg", "jpeg"];
+    let supported_exts = [
+        "png", "jpg", "jpeg", "gif", "tif", "tiff", "bmp", "tga", "webp", "avif", "svg",
+    ];
+    let is_supported = |p: &Path| has_ext(p, &supported_exts);
+
+    let mut widths = config.responsive_widths.clone();
+    widths.sort_unstable();
+    widths.dedup();
 
-    let is_supported = |p: &Path| {
-        p.extension()
-            .map(|ext| supported_exts.contains(&ext.to_string_lossy().to_lowercase().as_str()))
-            .unwrap_or(false)
+    let make_job = |src: &Path, root: &Path| FileJob {
+        src: src.to_path_buf(),
+        dest: resolve_output_path(src, root, config, None),
+        variants: widths
+            .iter()
+            .filter(|&&w| w > 0)
+            .map(|&w| (w, resolve_output_path(src, root, config, Some(w))))
+            .collect(),
+        content_hash: None,
+        skip: None,
+        blocked_sidecars: Vec::new(),
     };
 
     for task in &config.tasks {
@@ -134,13 +411,11 @@ fn collect_file_tasks(config: &OptimizeConfig) -> Result<Vec<(PathBuf, PathBuf)>
             for entry in WalkDir::new(src_path).into_iter().filter_map(|e| e.ok()) {
                 let path = entry.path();
                 if path.is_file() && is_supported(path) {
-                    let dest = resolve_output_path(path, root_path, config);
-                    tasks.push((path.to_path_buf(), dest));
+                    tasks.push(make_job(path, root_path));
                 }
             }
         } else if src_path.is_file() && is_supported(src_path) {
-            let dest = resolve_output_path(src_path, root_path, config);
-            tasks.push((src_path.to_path_buf(), dest));
+            tasks.push(make_job(src_path, root_path));
         }
     }
 
@@ -148,14 +423,71 @@ fn collect_file_tasks(config: &OptimizeConfig) -> Result<Vec<(PathBuf, PathBuf)>
         return Err("No supported files found.".to_string());
     }
 
-    tasks.sort_by(|a, b| a.0.cmp(&b.0));
-    tasks.dedup_by(|a, b| a.0 == b.0);
+    tasks.sort_by(|a, b| a.src.cmp(&b.src));
+    tasks.dedup_by(|a, b| a.src == b.src);
+
+    // photo.tiff next to photo.jpg would otherwise overwrite it or its output, and
+    // the WebP sidecar of photo.jpg would overwrite photo.webp. Main outputs win,
+    // so a sidecar is dropped when its name belongs to any other file of the run.
+    let sources: HashSet<PathBuf> = tasks.iter().map(|job| job.src.clone()).collect();
+    let mut dest_counts: HashMap<PathBuf, usize> = HashMap::new();
+    let mut sidecar_counts: HashMap<PathBuf, usize> = HashMap::new();
+    for job in &tasks {
+        *dest_counts.entry(job.dest.clone()).or_default() += 1;
+        for (_, path) in sidecar_outputs(job, config) {
+            *sidecar_counts.entry(path).or_default() += 1;
+        }
+    }
+    let may_collide =
+        |job: &FileJob| needs_conversion(&job.src) || has_ext(&job.src, &SIDECAR_EXTS);
+    for job in tasks.iter_mut() {
+        if may_collide(job) {
+            let taken = job.dest != job.src && sources.contains(&job.dest)
+                || overwrites_existing(&job.src, &job.dest, config);
+            if taken || dest_counts[&job.dest] > 1 {
+                job.skip = Some(NAME_TAKEN);
+            }
+        }
+        job.blocked_sidecars = sidecar_outputs(job, config)
+            .into_iter()
+            .filter(|(_, path)| {
+                sources.contains(path) || dest_counts.contains_key(path) || sidecar_counts[path] > 1
+            })
+            .map(|(ext, _)| ext)
+            .collect();
+    }
+
+    if let Some(manifest) = manifest {
+        tasks.par_iter_mut().for_each(|job| {
+            if job.skip.is_some() {
+                return;
+            }
+            let Ok(hash) = hash_file(&job.src) else {
+                return;
+            };
+            // Outputs written elsewhere may have been deleted since, so they must still exist
+            let outputs_exist = !config.optimize_original || job.dest.exists();
+            job.skip = if manifest.is_output(&hash) {
+                Some(EARLIER_OUTPUT)
+            } else if outputs_exist && manifest.contains(&hash) {
+                Some(ALREADY_OPTIMIZED)
+            } else {
+                None
+            };
+            job.content_hash = Some(hash);
+        });
+    }
 
     Ok(tasks)
 }
 
-fn resolve_output_path(src: &Path, root_source: &Path, config: &OptimizeConfig) -> PathBuf {
-    if let Some(ref out_dir_str) = config.output_dir {
+pub fn resolve_output_path(
+    src: &Path,
+    root_source: &Path,
+    config: &OptimizeConfig,
+    variant_width: Option<u32>,
+) -> PathBuf {
+    let dest = if let Some(ref out_dir_str) = config.output_dir {
         let out_base = Path::new(out_dir_str);
 
         if root_source.is_dir() {
@@ -172,140 +504,1005 @@ fn resolve_output_path(src: &Path, root_source: &Path, config: &OptimizeConfig)
         let ext = src.extension().unwrap_or_default().to_string_lossy();
         let new_name = format!("{}__optimized.{}", stem, ext);
         src.parent().unwrap_or(Path::new(".")).join(new_name)
+    };
+    let dest = if needs_conversion(src) {
+        dest.with_extension(config.convert_to.extension())
+    } else {
+        dest
+    };
+
+    match variant_width {
+        Some(width) => {
+            let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = dest.extension().unwrap_or_default().to_string_lossy();
+            dest.with_file_name(format!("{}-{}w.{}", stem, width, ext))
+        }
+        None => dest,
     }
 }
 
-#[allow(clippy::too_many_arguments)]
+fn has_ext(path: &Path, exts: &[&str]) -> bool {
+    path.extension()
+        .is_some_and(|ext| exts.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn needs_conversion(path: &Path) -> bool {
+    has_ext(path, &CONVERTED_EXTS)
+}
+
+// In replace mode a converted file lands next to its source, where the new
+// name may belong to a file that is not part of this run
+fn overwrites_existing(src: &Path, dest: &Path, config: &OptimizeConfig) -> bool {
+    config.replace
+        && config.output_dir.is_none()
+        && config.optimize_original
+        && !config.dry_run
+        && needs_conversion(src)
+        && dest.exists()
+}
+
+// Tool errors reach here with the output they belong to in front, as in
+// "640w variant: JPEG XL: Canceled"
+fn is_cancel_error(error: &str) -> bool {
+    error.rsplit(": ").next() == Some("Canceled")
+}
+
+// A file that already is in a sidecar format gets no sidecar of that format
+fn sidecar_outputs(job: &FileJob, config: &OptimizeConfig) -> Vec<(&'static str, PathBuf)> {
+    [
+        ("webp", config.webp),
+        ("avif", config.avif),
+        ("jxl", config.jxl),
+    ]
+    .into_iter()
+    .filter(|&(ext, enabled)| enabled && !has_ext(&job.dest, &[ext]))
+    .map(|(ext, _)| (ext, job.dest.with_extension(ext)))
+    .collect()
+}
+
 fn process_single_file(
-    src: &Path,
-    dest: &Path,
-    config: &OptimizeConfig,
-    pq: &ToolPath,
-    oxi: &ToolPath,
-    window: &Window,
+    job: &FileJob,
+    ctx: &RunContext,
+    sink: &dyn ProgressSink,
     done_counter: &Arc<AtomicU64>,
     total_files: u64,
-    should_cancel: &Arc<AtomicBool>,
 ) -> FileStats {
+    let file_name = job
+        .src
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    sink.file_start(&file_name);
+
+    let mut stats = match job.skip {
+        Some(reason) => FileStats::skipped(reason),
+        None => optimize_file(job, ctx),
+    };
+    stats.path = job.src.to_string_lossy().to_string();
+
+    if let Some(manifest) = ctx.manifest.filter(|_| !ctx.config.dry_run) {
+        record_processed(manifest, job, ctx.config, &stats);
+    }
+
+    let done = done_counter.fetch_add(1, Ordering::Relaxed) + 1;
+    sink.progress(ProgressPayload {
+        total: total_files,
+        done,
+        current_file: file_name,
+    });
+    sink.file_done(&stats);
+
+    stats
+}
+
+// In replace mode the next run sees the optimized bytes, so their hash is recorded too.
+// Files written next to the sources are remembered so later runs do not pick them up.
+fn record_processed(
+    manifest: &Manifest,
+    job: &FileJob,
+    config: &OptimizeConfig,
+    stats: &FileStats,
+) {
+    let Some(hash) = &job.content_hash else {
+        return;
+    };
+    if !matches!(
+        stats.outcome,
+        FileOutcome::Optimized | FileOutcome::Unchanged
+    ) {
+        return;
+    }
+    manifest.record(hash);
+
+    let rewritten = config.optimize_original && !stats.kept_original;
+    if job.src == job.dest && rewritten {
+        if let Ok(new_hash) = hash_file(&job.dest) {
+            manifest.record(&new_hash);
+        }
+    }
+
+    let mut outputs: Vec<PathBuf> = stats
+        .variants
+        .iter()
+        .map(|v| PathBuf::from(&v.path))
+        .collect();
+    if job.src != job.dest && rewritten {
+        outputs.push(job.dest.clone());
+    }
+    if stats.webp_size > 0 {
+        outputs.push(job.dest.with_extension("webp"));
+    }
+    if stats.avif_size > 0 {
+        outputs.push(job.dest.with_extension("avif"));
+    }
+    if stats.jxl_size > 0 {
+        outputs.push(job.dest.with_extension("jxl"));
+    }
+    for path in outputs {
+        if let Ok(output_hash) = hash_file(&path) {
+            manifest.record_output(&output_hash);
+        }
+    }
+}
+
+fn optimize_file(job: &FileJob, ctx: &RunContext) -> FileStats {
+    let RunContext {
+        config,
+        png,
+        backup,
+        should_cancel,
+        ..
+    } = *ctx;
     let t_start = Instant::now();
-    let _ = window.emit(
-        "file_start",
-        src.file_name().unwrap_or_default().to_string_lossy(),
-    );
+    let (src, dest) = (job.src.as_path(), job.dest.as_path());
+    let converting = needs_conversion(src);
 
     if should_cancel.load(Ordering::Relaxed) {
-        return FileStats::default();
+        return FileStats::skipped("Canceled");
+    }
+    if has_ext(src, &["webp"]) && is_animated_webp(src) {
+        return FileStats::skipped("Animated WebP is not re-encoded");
+    }
+    // The name may have been taken since the files were collected
+    if overwrites_existing(src, dest, config) {
+        return FileStats::skipped(NAME_TAKEN);
     }
 
-    if src != dest {
+    let original_size = match fs::metadata(src) {
+        Ok(m) => m.len(),
+        Err(e) => return FileStats::failed(format!("Failed to read file: {}", e)),
+    };
+
+    if let Some(store) = backup.filter(|_| config.optimize_original && src == dest) {
+        if let Err(e) = store.save(src) {
+            return FileStats::failed(format!("Failed to back up original: {}", e));
+        }
+    }
+
+    if src != dest && !config.dry_run {
         if let Some(parent) = dest.parent() {
-            let _ = fs::create_dir_all(parent);
+            if let Err(e) = fs::create_dir_all(parent) {
+                return FileStats::failed(format!("Failed to create output folder: {}", e));
+            }
         }
 
-        if config.optimize_original {
-            if fs::copy(src, dest).is_err() {
-                return FileStats::default();
+        if config.optimize_original && !converting {
+            let copied = fs::read(src)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| write_atomic(dest, &bytes));
+            if let Err(e) = copied {
+                return FileStats::failed(format!("Failed to copy file: {}", e));
+            }
+        }
+    }
+
+    let source_meta = read_metadata(src);
+    let orientation = source_meta.orientation();
+
+    // Re-encoders bake the rotation into pixels; PNG tools keep pixels, so the tag must survive
+    let mut meta = source_meta.filter(config.metadata);
+    let mut png_meta = meta.clone();
+    png_meta.set_orientation(orientation);
+    meta.set_orientation(1);
+
+    let mut stats = FileStats {
+        original_size,
+        ..Default::default()
+    };
+    let mut errors = Vec::new();
+    let out = (!config.dry_run).then_some(dest);
+
+    let ext = dest
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    let is_jpeg = ["jpg", "jpeg"].contains(&ext.as_str());
+    let is_gif = ext == "gif";
+    let is_svg = ext == "svg";
+    let is_sidecar_format = SIDECAR_EXTS.contains(&ext.as_str());
+
+    let animation =
+        if is_gif && (config.webp || config.avif || config.jxl || !job.variants.is_empty()) {
+            match decode_gif_animation(src) {
+                Ok(anim) => anim,
+                Err(e) => {
+                    errors.push(e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+    if animation.is_some() && (config.resize.is_some() || !job.variants.is_empty()) {
+        stats
+            .warnings
+            .push("Animated GIFs are not resized and get no responsive variants".into());
+    }
+
+    let blocked = |format: &str| job.blocked_sidecars.contains(&format);
+    for format in &job.blocked_sidecars {
+        let name = match *format {
+            "webp" => "WebP",
+            "avif" => "AVIF",
+            _ => "JPEG XL",
+        };
+        stats
+            .warnings
+            .push(format!("{} output skipped: {}", name, NAME_TAKEN));
+    }
+
+    let jpeg_transcode = is_jpeg && config.jxl_settings.lossless_jpeg;
+    let needs_pixels = !is_svg
+        && (config.webp
+            || config.avif
+            || (config.jxl && !(jpeg_transcode && config.resize.is_none()))
+            || !job.variants.is_empty()
+            || (config.optimize_original
+                && (is_jpeg
+                    || is_sidecar_format
+                    || converting
+                    || (config.resize.is_some() && !is_gif))));
+    if is_svg
+        && (config.webp
+            || config.avif
+            || config.jxl
+            || config.resize.is_some()
+            || !job.variants.is_empty())
+    {
+        stats
+            .warnings
+            .push("SVG files are only minified, raster outputs and resizing are skipped".into());
+    }
+    // Encoded AVIF cannot be decoded in-process, so it is never scored against the target
+    if config.target_ssim.is_some()
+        && !is_svg
+        && animation.is_none()
+        && (config.avif || ext == "avif")
+    {
+        stats.warnings.push(format!(
+            "Target SSIM does not apply to AVIF, encoded at quality {}",
+            config.avif_settings.quality
+        ));
+    }
+
+    let mut source = None;
+    let mut resized = None;
+    if needs_pixels {
+        match decode_image(src, png) {
+            Ok(decoded) => {
+                let decoded = apply_orientation(decoded, orientation);
+                if let Some(resize) = &config.resize {
+                    let out = resize_image(decoded.clone(), resize);
+                    if out.dimensions() != decoded.dimensions() {
+                        resized = Some(out);
+                    }
+                }
+                source = Some(decoded);
             }
+            Err(e) => errors.push(e),
         }
     }
+    let img = resized.as_ref().or(source.as_ref());
+    let budget = budget_for(config);
+    let lossless_source = ["png", "gif", "webp"].contains(&ext.as_str());
+    let webp_settings = resolve_webp_settings(&config.webp_settings, lossless_source, img);
+    let encode_ctx = EncodeContext {
+        config,
+        png,
+        meta: &meta,
+        webp_settings: &webp_settings,
+        budget,
+        original_size,
+    };
 
-    let original_size = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
-    let mut webp_size = 0;
-    let mut avif_size = 0;
-    let mut duration_webp = 0.0;
-    let mut duration_avif = 0.0;
+    if let Some(anim) = &animation {
+        if config.webp && !blocked("webp") && !should_cancel.load(Ordering::Relaxed) {
+            let t = Instant::now();
+            stats.webp_mode = Some(webp_settings.mode);
+            match generate_animated_webp(anim, out, &webp_settings, original_size) {
+                Ok(res) => {
+                    stats.webp_size = res.size;
+                    stats.webp_discarded = res.kept_original;
+                }
+                Err(e) => errors.push(format!("WebP: {}", e)),
+            }
+            stats.duration_webp = t.elapsed().as_secs_f64();
+        }
+        if config.avif {
+            stats
+                .warnings
+                .push("Animated AVIF is not supported by the encoder, skipped".into());
+        }
+    } else if let Some(img) = img {
+        // A file converted to WebP or AVIF already is that sidecar
+        if config.webp
+            && ext != "webp"
+            && !blocked("webp")
+            && !should_cancel.load(Ordering::Relaxed)
+        {
+            let t = Instant::now();
+            stats.webp_mode = Some(webp_settings.mode);
+            match generate_webp(
+                img,
+                out,
+                webp_quality(config, &webp_settings),
+                &webp_settings,
+                budget.as_ref(),
+                original_size,
+                &meta,
+            ) {
+                Ok(res) => {
+                    record_quality(&mut stats, config, "webp", &res);
+                    stats.webp_size = res.size;
+                    stats.webp_discarded = res.kept_original;
+                }
+                Err(e) => errors.push(format!("WebP: {}", e)),
+            }
+            stats.duration_webp = t.elapsed().as_secs_f64();
+        }
 
-    if config.webp || config.avif {
-        if let Ok(img) = image::open(src) {
-            if config.webp && !should_cancel.load(Ordering::Relaxed) {
-                let t = Instant::now();
-                webp_size = generate_webp(&img, dest, 75.0);
-                duration_webp = t.elapsed().as_secs_f64();
+        if config.avif
+            && ext != "avif"
+            && !blocked("avif")
+            && !should_cancel.load(Ordering::Relaxed)
+        {
+            let t = Instant::now();
+            let settings = &config.avif_settings;
+            match generate_avif(img, out, settings, budget.as_ref(), original_size, &meta) {
+                Ok(res) => {
+                    record_quality(&mut stats, config, "avif", &res);
+                    stats.avif_size = res.size;
+                    stats.avif_discarded = res.kept_original;
+                }
+                Err(e) => errors.push(format!("AVIF: {}", e)),
             }
+            stats.duration_avif = t.elapsed().as_secs_f64();
+        }
+    }
 
-            if config.avif && !should_cancel.load(Ordering::Relaxed) {
-                let t = Instant::now();
-                avif_size = generate_avif(&img, dest);
-                duration_avif = t.elapsed().as_secs_f64();
+    if config.jxl && !blocked("jxl") && !should_cancel.load(Ordering::Relaxed) {
+        let source = if jpeg_transcode && resized.is_none() {
+            Some(JxlSource::Jpeg(src))
+        } else if animation.is_some() {
+            Some(JxlSource::Gif(src))
+        } else {
+            img.map(JxlSource::Pixels)
+        };
+
+        if let Some(source) = source {
+            let t = Instant::now();
+            let settings = &config.jxl_settings;
+            match generate_jxl(source, out, settings, png, original_size, &meta) {
+                Ok(res) => {
+                    stats.jxl_size = res.size;
+                    stats.jxl_discarded = res.kept_original;
+                }
+                Err(e) => errors.push(format!("JPEG XL: {}", e)),
             }
+            stats.duration_jxl = t.elapsed().as_secs_f64();
         }
     }
 
     if should_cancel.load(Ordering::Relaxed) {
-        return FileStats {
-            bytes_saved: 0,
-            original_size,
-            optimized_size: original_size,
-            webp_size,
-            avif_size,
-            duration_opt: 0.0,
-            duration_webp,
-            duration_avif,
+        stats.optimized_size = original_size;
+        stats.outcome = FileOutcome::Skipped("Canceled".into());
+        return stats;
+    }
+
+    let t_opt_start = Instant::now();
+
+    if config.optimize_original {
+        let res = if converting {
+            match img {
+                Some(img) => write_converted(img, dest, &encode_ctx),
+                None => Ok(WriteResult {
+                    size: original_size,
+                    kept_original: true,
+                    ..Default::default()
+                }),
+            }
+        } else if ext == "png" {
+            if resized.is_some() {
+                process_png(src, out, img, original_size, png, &meta)
+            } else {
+                process_png(src, out, None, original_size, png, &png_meta)
+            }
+        } else if is_gif {
+            process_gif(src, out, original_size)
+        } else if is_svg {
+            process_svg(src, out, original_size, &config.svg_settings)
+        } else if let (true, Some(img)) = (is_jpeg, img) {
+            let quality = quality_for(config, config.jpg_q);
+            process_jpg(img, out, quality, budget.as_ref(), original_size, &meta)
+        } else if let (true, Some(img)) = (ext == "webp", img) {
+            stats.webp_mode = Some(webp_settings.mode);
+            let quality = webp_quality(config, &webp_settings);
+            let budget = budget.as_ref();
+            process_webp(
+                img,
+                out,
+                quality,
+                &webp_settings,
+                budget,
+                original_size,
+                &meta,
+            )
+        } else if let (true, Some(img)) = (ext == "avif", img) {
+            let settings = &config.avif_settings;
+            process_avif(img, out, settings, budget.as_ref(), original_size, &meta)
+        } else {
+            Ok(WriteResult {
+                size: original_size,
+                kept_original: true,
+                ..Default::default()
+            })
         };
+
+        match res {
+            Ok(res) => {
+                record_quality(&mut stats, config, &ext, &res);
+                stats.warnings.extend(res.warnings);
+                stats.tool_log.extend(res.tool_log);
+                stats.optimized_size = res.size;
+                stats.bytes_saved = original_size.saturating_sub(res.size);
+                stats.kept_original = res.kept_original;
+
+                // pngquant drives PNG quality, so for PNG the budget can only be checked afterwards
+                if let Some(max) = config.max_bytes.filter(|&max| res.size > max) {
+                    errors.push(format!(
+                        "Output is {} bytes, above the {} byte budget",
+                        res.size, max
+                    ));
+                }
+            }
+            Err(e) => {
+                stats.optimized_size = original_size;
+                errors.push(e);
+            }
+        }
     }
 
-    let ext = dest
+    let duration_opt_pure = t_opt_start.elapsed().as_secs_f64();
+
+    if let (Some(img), None) = (&source, &animation) {
+        let filter = config.resize.as_ref().map(|r| r.filter).unwrap_or_default();
+
+        for (width, path) in &job.variants {
+            if should_cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            if *width >= img.width() {
+                continue;
+            }
+
+            let small = resize_to_width(img, *width, filter);
+            if let Err(e) = write_variant(&small, *width, path, &encode_ctx, &mut stats) {
+                errors.push(format!("{}w variant: {}", width, e));
+            }
+        }
+    }
+
+    let total_file_time = t_start.elapsed().as_secs_f64();
+    let overhead = (total_file_time
+        - duration_opt_pure
+        - stats.duration_webp
+        - stats.duration_avif
+        - stats.duration_jxl)
+        .max(0.0);
+
+    if config.optimize_original {
+        stats.duration_opt = duration_opt_pure + overhead;
+    }
+
+    let produced_output = (config.optimize_original && !stats.kept_original)
+        || stats.webp_size > 0
+        || stats.avif_size > 0
+        || stats.jxl_size > 0
+        || !stats.variants.is_empty();
+
+    let killed_by_cancel = errors.iter().any(|e| is_cancel_error(e));
+
+    stats.outcome = if killed_by_cancel {
+        FileOutcome::Skipped("Canceled".into())
+    } else if !errors.is_empty() {
+        FileOutcome::Failed(errors.join("; "))
+    } else if produced_output {
+        FileOutcome::Optimized
+    } else {
+        FileOutcome::Unchanged
+    };
+
+    stats
+}
+
+fn quality_for(config: &OptimizeConfig, fixed: u8) -> QualityTarget {
+    match config.target_ssim {
+        Some(target) => QualityTarget::Ssim(target),
+        None => QualityTarget::Fixed(fixed),
+    }
+}
+
+// In lossless modes quality only trades encode effort for size, so there is nothing to search
+fn webp_quality(config: &OptimizeConfig, settings: &WebpSettings) -> QualityTarget {
+    match settings.mode {
+        WebpMode::Lossy | WebpMode::Auto => quality_for(config, settings.quality),
+        WebpMode::Lossless | WebpMode::NearLossless => QualityTarget::Fixed(settings.quality),
+    }
+}
+
+fn resolve_webp_settings(
+    settings: &WebpSettings,
+    lossless_source: bool,
+    img: Option<&DynamicImage>,
+) -> WebpSettings {
+    let mut resolved = settings.clone();
+    if settings.mode == WebpMode::Auto {
+        resolved.mode = match img {
+            Some(img) if lossless_source && looks_like_graphic(img) => WebpMode::Lossless,
+            _ => WebpMode::Lossy,
+        };
+    }
+    resolved
+}
+
+fn budget_for(config: &OptimizeConfig) -> Option<SizeBudget> {
+    config.max_bytes.map(|max_bytes| SizeBudget {
+        max_bytes,
+        allow_downscale: config.downscale_to_fit,
+    })
+}
+
+fn record_quality(stats: &mut FileStats, config: &OptimizeConfig, format: &str, res: &WriteResult) {
+    if config.target_ssim.is_none() && config.max_bytes.is_none() {
+        return;
+    }
+    if let Some(quality) = res.quality {
+        stats.qualities.push(QualityChoice {
+            format: format.to_string(),
+            quality,
+            ssim: res.score,
+            downscaled_to: res.downscaled,
+        });
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RunContext<'a> {
+    config: &'a OptimizeConfig,
+    png: &'a PngOptions,
+    backup: Option<&'a BackupStore>,
+    manifest: Option<&'a Manifest>,
+    should_cancel: &'a Arc<AtomicBool>,
+}
+
+struct EncodeContext<'a> {
+    config: &'a OptimizeConfig,
+    png: &'a PngOptions,
+    meta: &'a Metadata,
+    webp_settings: &'a WebpSettings,
+    budget: Option<SizeBudget>,
+    original_size: u64,
+}
+
+fn write_variant(
+    img: &DynamicImage,
+    width: u32,
+    path: &Path,
+    ctx: &EncodeContext,
+    stats: &mut FileStats,
+) -> Result<(), String> {
+    let config = ctx.config;
+    let out = (!config.dry_run).then_some(path);
+    let mut record = |path: PathBuf, size: u64| {
+        stats.variants.push(OutputFile {
+            path: path.to_string_lossy().to_string(),
+            width,
+            size,
+        });
+    };
+
+    let ext = path
         .extension()
         .unwrap_or_default()
         .to_string_lossy()
         .to_lowercase();
 
-    let t_opt_start = Instant::now();
+    // Resized GIF frames have no encoder, those variants only come as WebP/AVIF
+    if config.optimize_original && ext != "gif" {
+        let res = write_converted(img, path, ctx)?;
+        record(path.to_path_buf(), res.size);
+    }
 
-    let (new_size, bytes_saved) = if config.optimize_original {
-        if src != dest && !dest.exists() {
-             (0, 0)
-        } else {
-            let size = if ext == "png" {
-                process_png(dest, pq, oxi, config.png_min, config.png_max)
-            } else if ["jpg", "jpeg"].contains(&ext.as_str()) {
-                process_jpg(dest, config.jpg_q)
-            } else {
-                original_size
-            };
+    if config.webp && ext != "webp" {
+        let res = generate_webp(
+            img,
+            out,
+            webp_quality(config, ctx.webp_settings),
+            ctx.webp_settings,
+            ctx.budget.as_ref(),
+            ctx.original_size,
+            ctx.meta,
+        )?;
+        if res.size > 0 {
+            record(path.with_extension("webp"), res.size);
+        }
+    }
 
-            let saved = if original_size > size {
-                original_size - size
-            } else {
-                0
-            };
-            (size, saved)
+    if config.avif && ext != "avif" {
+        let res = generate_avif(
+            img,
+            out,
+            &config.avif_settings,
+            ctx.budget.as_ref(),
+            ctx.original_size,
+            ctx.meta,
+        )?;
+        if res.size > 0 {
+            record(path.with_extension("avif"), res.size);
         }
-    } else {
-        (0, 0)
-    };
+    }
 
-    let duration_opt_pure = t_opt_start.elapsed().as_secs_f64();
+    if config.jxl {
+        let res = generate_jxl(
+            JxlSource::Pixels(img),
+            out,
+            &config.jxl_settings,
+            ctx.png,
+            ctx.original_size,
+            ctx.meta,
+        )?;
+        if res.size > 0 {
+            record(path.with_extension("jxl"), res.size);
+        }
+    }
 
-    let done = done_counter.fetch_add(1, Ordering::Relaxed) + 1;
-    let _ = window.emit(
-        "progress",
-        ProgressPayload {
-            total: total_files,
-            done,
-            current_file: src
-                .file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string(),
-        },
-    );
+    Ok(())
+}
 
-    let total_file_time = t_start.elapsed().as_secs_f64();
-    let overhead = (total_file_time - duration_opt_pure - duration_webp - duration_avif).max(0.0);
+// Encodes into the format named by the extension of `path`. There is no source
+// file in that format to fall back to, so the result is always written.
+fn write_converted(
+    img: &DynamicImage,
+    path: &Path,
+    ctx: &EncodeContext,
+) -> Result<WriteResult, String> {
+    let config = ctx.config;
+    let out = (!config.dry_run).then_some(path);
+    let budget = ctx.budget.as_ref();
+    let ext = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
 
-    FileStats {
-        bytes_saved,
-        original_size,
-        optimized_size: new_size,
-        webp_size,
-        avif_size,
-        duration_opt: if config.optimize_original {
-            duration_opt_pure + overhead
-        } else {
-            0.0
-        },
-        duration_webp,
-        duration_avif,
+    match ext.as_str() {
+        "png" => {
+            let data = encode_png(img)?;
+            if let Some(path) = out {
+                write_atomic(path, &data)?;
+            }
+            let res = process_png(path, out, Some(img), data.len() as u64, ctx.png, ctx.meta)?;
+            Ok(WriteResult {
+                kept_original: false,
+                ..res
+            })
+        }
+        "jpg" | "jpeg" => {
+            let quality = quality_for(config, config.jpg_q);
+            process_jpg(img, out, quality, budget, u64::MAX, ctx.meta)
+        }
+        "webp" => {
+            let quality = webp_quality(config, ctx.webp_settings);
+            process_webp(
+                img,
+                out,
+                quality,
+                ctx.webp_settings,
+                budget,
+                u64::MAX,
+                ctx.meta,
+            )
+        }
+        "avif" => process_avif(img, out, &config.avif_settings, budget, u64::MAX, ctx.meta),
+        _ => Err(format!("Cannot encode .{} files", ext)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{FinalResult, ProgressPayload};
+    use image::{Rgb, RgbImage};
+    use serde_json::{json, Value};
+
+    struct NullSink;
+
+    impl ProgressSink for NullSink {
+        fn status(&self, _: &str) {}
+        fn file_start(&self, _: &str) {}
+        fn progress(&self, _: ProgressPayload) {}
+        fn file_done(&self, _: &FileStats) {}
+    }
+
+    fn config(files: &[&Path], root: &Path, extra: Value) -> OptimizeConfig {
+        let tasks: Vec<Value> = files
+            .iter()
+            .map(|path| json!({ "path": path, "root": root }))
+            .collect();
+        let mut value = json!({
+            "tasks": tasks,
+            "jpg_q": 80,
+            "png_min": 60,
+            "png_max": 80,
+            "webp": false,
+            "avif": false,
+            "replace": false,
+            "output_dir": null,
+        });
+        if let (Value::Object(base), Value::Object(extra)) = (&mut value, extra) {
+            base.extend(extra);
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn write_jpeg(path: &Path, img: RgbImage, meta: &Metadata) {
+        let img = DynamicImage::ImageRgb8(img);
+        let quality = QualityTarget::Fixed(100);
+        process_jpg(&img, Some(path), quality, None, u64::MAX, meta).unwrap();
+    }
+
+    fn run(config: OptimizeConfig) -> FinalResult {
+        perform_optimization(&NullSink, config, Arc::new(AtomicBool::new(false))).unwrap()
+    }
+
+    #[test]
+    fn rotated_jpeg_is_stored_upright_with_orientation_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+
+        // Red in the stored bottom-left corner, which orientation 6 shows top-left
+        // Textured, so re-encoding at a lower quality is smaller than the source
+        let img = RgbImage::from_fn(64, 32, |x, y| {
+            if x < 16 && y >= 16 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, ((x * 37 + y * 91) % 97) as u8, 255])
+            }
+        });
+        let mut meta = Metadata::default();
+        meta.set_orientation(6);
+        write_jpeg(&src, img, &meta);
+        assert_eq!(read_metadata(&src).orientation(), 6);
+
+        let config = config(&[&src], dir.path(), json!({ "metadata": "keep_all" }));
+        let dest = resolve_output_path(&src, dir.path(), &config, None);
+        let result = run(config);
+        assert!(!result.files[0].kept_original);
+
+        let out = image::open(&dest).unwrap().to_rgb8();
+        assert_eq!(out.dimensions(), (32, 64));
+        assert!(out.get_pixel(2, 2)[0] > 200 && out.get_pixel(2, 2)[2] < 60);
+        assert_eq!(read_metadata(&dest).orientation(), 1);
+    }
+
+    #[test]
+    fn target_ssim_with_avif_output_warns() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb([x as u8 * 16, y as u8 * 16, 128]));
+        write_jpeg(&src, img, &Metadata::default());
+
+        let extra = json!({ "avif": true, "target_ssim": 0.98 });
+        let result = run(config(&[&src], dir.path(), extra));
+        let warnings = &result.files[0].warnings;
+        let expected = "Target SSIM does not apply to AVIF";
+        assert!(warnings.iter().any(|w| w.starts_with(expected)));
+    }
+
+    fn textured(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([0, ((x * 37 + y * 91) % 97) as u8, 255])
+        })
+    }
+
+    #[test]
+    fn undone_files_are_processed_again_by_an_incremental_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        write_jpeg(&src, textured(64, 64), &Metadata::default());
+        let original = fs::read(&src).unwrap();
+
+        let backups = dir.path().join("backups");
+        let manifest = dir.path().join("manifest.json");
+        let extra = json!({
+            "replace": true,
+            "backup": true,
+            "backup_dir": backups,
+            "incremental": true,
+            "manifest_path": manifest,
+        });
+        let config = config(&[&src], dir.path(), extra);
+
+        let first = run(config.clone());
+        assert!(matches!(first.files[0].outcome, FileOutcome::Optimized));
+
+        let report = crate::backup::undo_last_run(&backups, Some(&manifest)).unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(fs::read(&src).unwrap(), original);
+
+        let second = run(config);
+        assert!(matches!(second.files[0].outcome, FileOutcome::Optimized));
+    }
+
+    #[test]
+    fn unchanged_incremental_run_keeps_the_last_backup_undoable() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        write_jpeg(&src, textured(64, 64), &Metadata::default());
+        let original = fs::read(&src).unwrap();
+
+        let backups = dir.path().join("backups");
+        let manifest = dir.path().join("manifest.json");
+        let extra = json!({
+            "replace": true,
+            "backup": true,
+            "backup_dir": backups,
+            "incremental": true,
+            "manifest_path": manifest,
+        });
+        let config = config(&[&src], dir.path(), extra);
+
+        let first = run(config.clone());
+        assert!(matches!(first.files[0].outcome, FileOutcome::Optimized));
+        let second = run(config);
+        assert!(matches!(second.files[0].outcome, FileOutcome::Skipped(_)));
+        assert!(second.backup_run.is_none());
+
+        let report = crate::backup::undo_last_run(&backups, Some(&manifest)).unwrap();
+        assert_eq!(report.restored.len(), 1);
+        assert_eq!(fs::read(&src).unwrap(), original);
+    }
+
+    fn listing(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| {
+                (
+                    e.path().to_path_buf(),
+                    fs::read(e.path()).unwrap_or_default(),
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        let tiff = dir.path().join("scan.tiff");
+        write_jpeg(&src, textured(64, 64), &Metadata::default());
+        textured(32, 32).save(&tiff).unwrap();
+        let before = listing(dir.path());
+
+        let extra = json!({
+            "replace": true,
+            "webp": true,
+            "responsive_widths": [16],
+            "backup": true,
+            "backup_dir": dir.path().join("backups"),
+            "incremental": true,
+            "manifest_path": dir.path().join("manifest.json"),
+            "dry_run": true,
+        });
+        let result = run(config(&[&src, &tiff], dir.path(), extra));
+
+        assert!(result.is_estimate);
+        assert!(result.total_size_webp > 0);
+        assert_eq!(listing(dir.path()), before);
+    }
+
+    #[test]
+    fn replace_mode_conversion_keeps_an_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.tiff");
+        let existing = dir.path().join("photo.png");
+        textured(32, 32).save(&src).unwrap();
+        fs::write(&existing, b"not part of this run").unwrap();
+
+        let extra = json!({ "replace": true, "convert_to": "png" });
+        let result = run(config(&[&src], dir.path(), extra));
+
+        assert!(matches!(&result.files[0].outcome, FileOutcome::Skipped(r) if r == NAME_TAKEN));
+        assert_eq!(fs::read(&existing).unwrap(), b"not part of this run");
+    }
+
+    #[test]
+    fn sidecar_does_not_overwrite_a_source_with_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let jpeg = dir.path().join("photo.jpg");
+        let webp = dir.path().join("photo.webp");
+        write_jpeg(&jpeg, textured(32, 32), &Metadata::default());
+        let pixels = textured(32, 32);
+        let encoded = webp::Encoder::from_rgb(&pixels, 32, 32).encode_lossless();
+        fs::write(&webp, &*encoded).unwrap();
+
+        let extra = json!({ "replace": true, "webp": true });
+        let result = run(config(&[&jpeg, &webp], dir.path(), extra));
+
+        let file = |name: &str| {
+            result
+                .files
+                .iter()
+                .find(|f| f.path.ends_with(name))
+                .unwrap()
+        };
+        assert_eq!(file("photo.jpg").webp_size, 0);
+        assert!(file("photo.jpg")
+            .warnings
+            .iter()
+            .any(|w| w.starts_with("WebP output skipped")));
+        assert!(!matches!(
+            file("photo.webp").outcome,
+            FileOutcome::Skipped(_)
+        ));
+    }
+
+    #[test]
+    fn avif_chroma_subsampling_other_than_444_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        write_jpeg(&src, textured(16, 16), &Metadata::default());
+
+        let extra = json!({ "avif": true, "avif_settings": { "chroma_subsampling": "yuv420" } });
+        let config = config(&[&src], dir.path(), extra);
+        let result = perform_optimization(&NullSink, config, Arc::new(AtomicBool::new(false)));
+
+        assert!(matches!(result, Err(e) if e.contains("4:4:4")));
+    }
+
+    #[test]
+    fn byte_budget_with_jxl_output_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        write_jpeg(&src, textured(16, 16), &Metadata::default());
+
+        let extra = json!({ "jxl": true, "max_bytes": 1000 });
+        let config = config(&[&src], dir.path(), extra);
+        let result = perform_optimization(&NullSink, config, Arc::new(AtomicBool::new(false)));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn prefixed_tool_cancellation_counts_as_cancel() {
+        assert!(is_cancel_error("Canceled"));
+        assert!(is_cancel_error("JPEG XL: Canceled"));
+        assert!(is_cancel_error("640w variant: JPEG XL: Canceled"));
+        assert!(!is_cancel_error("JPEG XL: cjxl timed out after 300s"));
     }
 }
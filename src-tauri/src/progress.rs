@@ -0,0 +1,8 @@
+use crate::types::{FileStats, ProgressPayload};
+
+pub trait ProgressSink: Send + Sync {
+    fn status(&self, message: &str);
+    fn file_start(&self, file_name: &str);
+    fn progress(&self, payload: ProgressPayload);
+    fn file_done(&self, stats: &FileStats);
+}
@@ -0,0 +1,261 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, ImageFormat};
+
+const WINDOW: u32 = 8;
+const STEP: u32 = 4;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+const DOWNSCALE_STEP: f64 = 0.85;
+const MIN_DIMENSION: u32 = 64;
+const MIN_QUALITY: u8 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub enum QualityTarget {
+    Fixed(u8),
+    Ssim(f64),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SizeBudget {
+    pub max_bytes: u64,
+    pub allow_downscale: bool,
+}
+
+pub struct Encoded {
+    pub data: Vec<u8>,
+    pub quality: u8,
+    pub score: Option<f64>,
+    pub downscaled: Option<(u32, u32)>,
+}
+
+pub fn ssim(reference: &GrayImage, candidate: &GrayImage) -> f64 {
+    if reference.dimensions() != candidate.dimensions() {
+        return 0.0;
+    }
+
+    let (width, height) = reference.dimensions();
+    if width < WINDOW || height < WINDOW {
+        return window_ssim(reference, candidate, 0, 0, width, height);
+    }
+
+    let mut total = 0.0;
+    let mut count = 0u64;
+    let mut y = 0;
+    while y + WINDOW <= height {
+        let mut x = 0;
+        while x + WINDOW <= width {
+            total += window_ssim(reference, candidate, x, y, WINDOW, WINDOW);
+            count += 1;
+            x += STEP;
+        }
+        y += STEP;
+    }
+
+    total / count.max(1) as f64
+}
+
+fn window_ssim(a: &GrayImage, b: &GrayImage, x0: u32, y0: u32, w: u32, h: u32) -> f64 {
+    let n = (w * h).max(1) as f64;
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+
+    for y in y0..y0 + h {
+        for x in x0..x0 + w {
+            let pa = a.get_pixel(x, y)[0] as f64;
+            let pb = b.get_pixel(x, y)[0] as f64;
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let cov = sum_ab / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+pub fn validate_target(target: f64) -> Result<(), String> {
+    if target > 0.0 && target < 1.0 {
+        Ok(())
+    } else {
+        Err(format!(
+            "Target SSIM must be between 0 and 1, got {}",
+            target
+        ))
+    }
+}
+
+// Finds the lowest quality whose decoded output reaches the target SSIM.
+// Falls back to quality 100 when the target cannot be reached at all.
+pub fn encode_with_target<F>(
+    img: &DynamicImage,
+    target: QualityTarget,
+    format: ImageFormat,
+    encode: F,
+) -> Result<Encoded, String>
+where
+    F: Fn(u8) -> Result<Vec<u8>, String>,
+{
+    let goal = match target {
+        QualityTarget::Fixed(quality) => {
+            return Ok(Encoded {
+                data: encode(quality)?,
+                quality,
+                score: None,
+                downscaled: None,
+            })
+        }
+        QualityTarget::Ssim(goal) => goal,
+    };
+
+    let reference = img.to_luma8();
+    let measure = |quality: u8| -> Result<Encoded, String> {
+        let data = encode(quality)?;
+        let decoded = image::load_from_memory_with_format(&data, format)
+            .map_err(|e| format!("Failed to decode candidate: {}", e))?;
+        let score = ssim(&reference, &decoded.to_luma8());
+        Ok(Encoded {
+            data,
+            quality,
+            score: Some(score),
+            downscaled: None,
+        })
+    };
+
+    let (mut lo, mut hi) = (MIN_QUALITY, 100u8);
+    let mut best = None;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let candidate = measure(mid)?;
+        if candidate.score.unwrap_or(0.0) >= goal {
+            hi = mid;
+            best = Some(candidate);
+        } else {
+            lo = mid + 1;
+        }
+    }
+
+    match best {
+        Some(best) => Ok(best),
+        None => measure(hi),
+    }
+}
+
+// Lowers quality (never above the one already chosen) and, when allowed, the
+// dimensions until the output fits. Errors instead of returning oversized data.
+pub fn fit_budget<F>(
+    img: &DynamicImage,
+    encoded: Encoded,
+    budget: Option<&SizeBudget>,
+    encode: F,
+) -> Result<Encoded, String>
+where
+    F: Fn(&DynamicImage, u8) -> Result<Vec<u8>, String>,
+{
+    let budget = match budget {
+        Some(b) if encoded.data.len() as u64 > b.max_bytes => b,
+        _ => return Ok(encoded),
+    };
+
+    let max_quality = encoded.quality;
+    // A configured quality below the usual floor is itself the floor
+    let min_quality = MIN_QUALITY.min(max_quality);
+    let mut smallest = encoded.data.len();
+    let mut current = None::<DynamicImage>;
+
+    loop {
+        let frame = current.as_ref().unwrap_or(img);
+
+        let (mut lo, mut hi) = (min_quality, max_quality);
+        let mut best = None;
+        while lo <= hi {
+            let mid = lo + (hi - lo) / 2;
+            let data = encode(frame, mid)?;
+            smallest = smallest.min(data.len());
+            if data.len() as u64 <= budget.max_bytes {
+                best = Some((mid, data));
+                lo = mid + 1;
+            } else if mid == min_quality {
+                break;
+            } else {
+                hi = mid - 1;
+            }
+        }
+
+        if let Some((quality, data)) = best {
+            return Ok(Encoded {
+                data,
+                quality,
+                score: None,
+                downscaled: current.as_ref().map(|c| c.dimensions()),
+            });
+        }
+
+        let (width, height) = frame.dimensions();
+        if !budget.allow_downscale || width.min(height) <= MIN_DIMENSION {
+            return Err(format!(
+                "Could not fit within {} bytes (smallest attempt was {} bytes)",
+                budget.max_bytes, smallest
+            ));
+        }
+
+        let new_width = ((width as f64 * DOWNSCALE_STEP) as u32).max(1);
+        let new_height = ((height as f64 * DOWNSCALE_STEP) as u32).max(1);
+        current = Some(frame.resize_exact(new_width, new_height, FilterType::Triangle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    // Output size grows with quality and pixel count, like a real encoder
+    fn fake_encode(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+        let pixels = (img.width() * img.height()) as usize;
+        Ok(vec![0; pixels / 256 + quality as usize * 10])
+    }
+
+    fn fit(quality: u8, max_bytes: u64, allow_downscale: bool) -> Result<Encoded, String> {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(256, 256));
+        let encoded = Encoded {
+            data: fake_encode(&img, quality)?,
+            quality,
+            score: None,
+            downscaled: None,
+        };
+        let budget = SizeBudget {
+            max_bytes,
+            allow_downscale,
+        };
+        fit_budget(&img, encoded, Some(&budget), fake_encode)
+    }
+
+    #[test]
+    fn fit_budget_picks_the_highest_quality_that_fits() {
+        let fitted = fit(80, 756, false).unwrap();
+        assert_eq!(fitted.quality, 50);
+        assert_eq!(fitted.downscaled, None);
+    }
+
+    #[test]
+    fn fit_budget_keeps_a_configured_quality_below_the_floor() {
+        let fitted = fit(5, 306, false).unwrap();
+        assert_eq!(fitted.quality, 5);
+
+        let fitted = fit(5, 250, true).unwrap();
+        assert_eq!(fitted.quality, 5);
+        assert_eq!(fitted.downscaled, Some((217, 217)));
+    }
+
+    #[test]
+    fn fit_budget_errors_when_nothing_fits() {
+        assert!(fit(5, 250, false).is_err());
+        assert!(fit(80, 10, true).is_err());
+    }
+}
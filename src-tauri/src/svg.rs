@@ -0,0 +1,420 @@
+use image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use roxmltree::{Document, Node, ParsingOptions};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use crate::types::SvgSettings;
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+
+// Editor and RDF namespaces; nothing in them affects rendering
+const EDITOR_NAMESPACES: [&str; 19] = [
+    "http://www.inkscape.org/namespaces/inkscape",
+    "http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd",
+    "http://www.bohemiancoding.com/sketch/ns",
+    "http://www.figma.com/figma/ns",
+    "http://ns.adobe.com/AdobeIllustrator/10.0/",
+    "http://ns.adobe.com/AdobeSVGViewerExtensions/3.0/",
+    "http://ns.adobe.com/Extensibility/1.0/",
+    "http://ns.adobe.com/Flows/1.0/",
+    "http://ns.adobe.com/GenericCustomNamespace/1.0/",
+    "http://ns.adobe.com/Graphs/1.0/",
+    "http://ns.adobe.com/ImageReplacement/1.0/",
+    "http://ns.adobe.com/SaveForWeb/1.0/",
+    "http://ns.adobe.com/Variables/1.0/",
+    "http://ns.adobe.com/XPath/1.0/",
+    "http://schemas.microsoft.com/visio/2003/SVGExtensions/",
+    "http://taptrix.com/vectorillustrator/svg_extensions",
+    "http://www.w3.org/1999/02/22-rdf-syntax-ns#",
+    "http://purl.org/dc/elements/1.1/",
+    "http://creativecommons.org/ns#",
+];
+
+// Whitespace between children of these is content
+const TEXT_ELEMENTS: [&str; 5] = ["text", "tspan", "textPath", "style", "script"];
+
+const NUMERIC_ATTRS: [&str; 21] = [
+    "d",
+    "points",
+    "viewBox",
+    "x",
+    "y",
+    "width",
+    "height",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "fx",
+    "fy",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "stroke-width",
+    "stroke-dasharray",
+    "stroke-dashoffset",
+];
+
+const TRANSFORM_ATTRS: [&str; 3] = ["transform", "gradientTransform", "patternTransform"];
+
+// Rebuilds the document without comments, processing instructions, the DTD,
+// editor data and redundant attributes, with numbers rounded to the set precision
+pub fn minify_svg(text: &str, settings: &SvgSettings) -> Result<String, String> {
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = Document::parse_with_options(text, options)
+        .map_err(|e| format!("Failed to parse SVG: {}", e))?;
+
+    let mut out = String::with_capacity(text.len());
+    write_element(doc.root_element(), settings, &mut out);
+    Ok(out)
+}
+
+fn write_element(node: Node, settings: &SvgSettings, out: &mut String) {
+    let tag = node.tag_name();
+    if tag.namespace().is_some_and(is_editor_namespace)
+        || (tag.namespace() == Some(SVG_NS) && tag.name() == "metadata")
+    {
+        return;
+    }
+
+    // Editors often bind an svg: prefix next to the default namespace; the default is shorter
+    let name = if tag.namespace().is_some() && tag.namespace() == node.default_namespace() {
+        tag.name().to_string()
+    } else {
+        qualified_name(node, tag.namespace(), tag.name())
+    };
+    let mut start = format!("<{}", name);
+
+    let parent_namespaces: Vec<_> = node
+        .parent_element()
+        .map(|p| p.namespaces().map(|ns| (ns.name(), ns.uri())).collect())
+        .unwrap_or_default();
+    for ns in node.namespaces() {
+        let declared_above = parent_namespaces.contains(&(ns.name(), ns.uri()));
+        let shadowed_prefix = ns.name().is_some() && Some(ns.uri()) == node.default_namespace();
+        if declared_above
+            || shadowed_prefix
+            || ns.name() == Some("xml")
+            || is_editor_namespace(ns.uri())
+        {
+            continue;
+        }
+        match ns.name() {
+            Some(prefix) => start.push_str(&format!(" xmlns:{}=\"", prefix)),
+            None => start.push_str(" xmlns=\""),
+        }
+        start.push_str(&escape(ns.uri(), true));
+        start.push('"');
+    }
+
+    let mut kept_attrs = 0;
+    for attr in node.attributes() {
+        if attr.namespace().is_some_and(is_editor_namespace)
+            || is_redundant(node, attr.name(), attr.value())
+        {
+            continue;
+        }
+
+        let value = if attr.namespace().is_some() {
+            attr.value().to_string()
+        } else if NUMERIC_ATTRS.contains(&attr.name()) {
+            round_numbers(
+                attr.value(),
+                settings.precision as usize,
+                attr.name() == "d",
+            )
+        } else if TRANSFORM_ATTRS.contains(&attr.name()) {
+            // Matrix terms scale everything after them, so they keep more digits
+            round_numbers(attr.value(), settings.precision as usize + 2, false)
+        } else {
+            attr.value().to_string()
+        };
+
+        let name = qualified_name(node, attr.namespace(), attr.name());
+        start.push_str(&format!(" {}=\"{}\"", name, escape(value.trim(), true)));
+        kept_attrs += 1;
+    }
+
+    let keep_whitespace = node
+        .ancestors()
+        .any(|a| TEXT_ELEMENTS.contains(&a.tag_name().name()));
+
+    let mut body = String::new();
+    for child in node.children() {
+        if child.is_element() {
+            write_element(child, settings, &mut body);
+        } else if let Some(text) = child.text().filter(|_| child.is_text()) {
+            if keep_whitespace || !text.trim().is_empty() {
+                body.push_str(&escape(text, false));
+            }
+        }
+    }
+
+    // Groups and defs that ended up empty render nothing
+    if body.is_empty() && kept_attrs == 0 && ["g", "defs"].contains(&tag.name()) {
+        return;
+    }
+
+    out.push_str(&start);
+    if body.is_empty() {
+        out.push_str("/>");
+    } else {
+        out.push('>');
+        out.push_str(&body);
+        out.push_str(&format!("</{}>", name));
+    }
+}
+
+fn is_editor_namespace(uri: &str) -> bool {
+    EDITOR_NAMESPACES.contains(&uri)
+}
+
+fn qualified_name(node: Node, namespace: Option<&str>, name: &str) -> String {
+    match namespace.and_then(|uri| node.lookup_prefix(uri)) {
+        Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, name),
+        _ => name.to_string(),
+    }
+}
+
+fn is_redundant(node: Node, name: &str, value: &str) -> bool {
+    let tag = node.tag_name().name();
+    let is_root = node.parent_element().is_none();
+    let is_zero = value.trim().trim_end_matches("px").parse::<f64>() == Ok(0.0);
+
+    match name {
+        "version" | "baseProfile" => is_root,
+        "enable-background" => true,
+        "id" | "class" | "style" => value.trim().is_empty(),
+        // Position of the outermost svg is ignored, for these elements 0 is the default
+        "x" | "y" => is_zero && (is_root || ["rect", "use", "image"].contains(&tag)),
+        // Not inherited, so the default cannot override a parent's value
+        "opacity" => value.trim() == "1",
+        _ => false,
+    }
+}
+
+// Only numbers written with a fraction or exponent are rounded. Arc flags in
+// path data are single characters that minifiers pack against the next number,
+// as in "a.5.5 0 01.5.5", so they are split off instead of read as "01.5".
+fn round_numbers(value: &str, precision: usize, is_path: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut out = String::with_capacity(value.len());
+    let mut last_has_dot = false;
+    let mut command = b' ';
+    let mut arg = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let is_flag = is_path
+            && command == b'a'
+            && matches!(arg % 7, 3 | 4)
+            && matches!(bytes[i], b'0' | b'1');
+        let (end, number) = if is_flag {
+            (i + 1, value[i..i + 1].to_string())
+        } else if let Some((end, is_fraction)) = scan_number(bytes, i) {
+            let token = &value[i..end];
+            let number = match token.parse::<f64>() {
+                Ok(n) if is_fraction => format_number(n, precision),
+                _ => token.to_string(),
+            };
+            (end, number)
+        } else {
+            let ch = value[i..].chars().next().unwrap_or_default();
+            if ch.is_ascii_alphabetic() {
+                command = ch.to_ascii_lowercase() as u8;
+                arg = 0;
+            }
+            out.push(ch);
+            i += ch.len_utf8().max(1);
+            continue;
+        };
+        arg += 1;
+
+        // A dropped sign or fraction must not merge this number into the previous one
+        let needs_space = match (out.chars().last(), number.chars().next()) {
+            (Some(prev), Some(next)) if prev.is_ascii_digit() || prev == '.' => {
+                next.is_ascii_digit() || (next == '.' && !last_has_dot)
+            }
+            _ => false,
+        };
+        if needs_space {
+            out.push(' ');
+        }
+
+        last_has_dot = number.contains('.');
+        out.push_str(&number);
+        i = end;
+    }
+
+    out
+}
+
+// Returns where the number starting at `start` ends and whether it has a
+// fraction or exponent, or None when no digits start there
+fn scan_number(bytes: &[u8], start: usize) -> Option<(usize, bool)> {
+    let mut j = start;
+    if j < bytes.len() && matches!(bytes[j], b'-' | b'+') {
+        j += 1;
+    }
+    let digits = j;
+    while j < bytes.len() && bytes[j].is_ascii_digit() {
+        j += 1;
+    }
+    let mut has_dot = false;
+    if j < bytes.len() && bytes[j] == b'.' {
+        has_dot = true;
+        j += 1;
+        while j < bytes.len() && bytes[j].is_ascii_digit() {
+            j += 1;
+        }
+    }
+    if j - digits - has_dot as usize == 0 {
+        return None;
+    }
+
+    let mut has_exp = false;
+    if j < bytes.len() && matches!(bytes[j], b'e' | b'E') {
+        let mut k = j + 1;
+        if k < bytes.len() && matches!(bytes[k], b'-' | b'+') {
+            k += 1;
+        }
+        let exp_digits = k;
+        while k < bytes.len() && bytes[k].is_ascii_digit() {
+            k += 1;
+        }
+        if k > exp_digits {
+            has_exp = true;
+            j = k;
+        }
+    }
+
+    Some((j, has_dot || has_exp))
+}
+
+fn format_number(n: f64, precision: usize) -> String {
+    let fixed = format!("{:.*}", precision, n);
+    let trimmed = if fixed.contains('.') {
+        fixed.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &fixed
+    };
+
+    if trimmed == "-0" {
+        "0".to_string()
+    } else if let Some(rest) = trimmed.strip_prefix("-0.") {
+        format!("-.{}", rest)
+    } else if let Some(rest) = trimmed.strip_prefix("0.") {
+        format!(".{}", rest)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+// In text only `]]>` needs its `>` escaped, which keeps CSS child selectors readable
+fn escape(text: &str, attr: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' if !attr && out.ends_with("]]") => out.push_str("&gt;"),
+            '"' if attr => out.push_str("&quot;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+// Scales the drawing to fit `size` in both directions, so small icons stay sharp
+pub fn render_svg(path: &Path, size: u32) -> Result<DynamicImage, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
+    let options = usvg::Options {
+        resources_dir: path.parent().map(Path::to_path_buf),
+        fontdb: system_fonts(),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_data(&data, &options)
+        .map_err(|e| format!("Failed to parse SVG: {}", e))?;
+
+    let (width, height) = (tree.size().width(), tree.size().height());
+    let scale = (size as f32 / width).min(size as f32 / height);
+    let mut pixmap = tiny_skia::Pixmap::new(
+        ((width * scale).round() as u32).max(1),
+        ((height * scale).round() as u32).max(1),
+    )
+    .ok_or_else(|| "Failed to render SVG: invalid size".to_string())?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    let rgba = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(pixmap.width(), pixmap.height(), rgba)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "Failed to render SVG".to_string())
+}
+
+// Loading system fonts is slow, so it happens once for all thumbnails
+fn system_fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut db = usvg::fontdb::Database::new();
+            db.load_system_fonts();
+            Arc::new(db)
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_arc_flags_are_not_read_as_numbers() {
+        let round = |d: &str| round_numbers(d, 3, true);
+
+        assert_eq!(round("M2 2a.5.5 0 01.5.5"), "M2 2a.5.5 0 0 1 .5.5");
+        assert_eq!(round("a4 4 0 11-2 2"), "a4 4 0 1 1-2 2");
+        assert_eq!(round("A3.25 3.25 0 0010 10"), "A3.25 3.25 0 0 0 10 10");
+        // Repeated arguments of one command carry their own flags
+        assert_eq!(
+            round("a1 1 0 001 1 1 1 0 102.5 2"),
+            "a1 1 0 0 0 1 1 1 1 0 1 0 2.5 2"
+        );
+        assert_eq!(round("M0 0L10.12345 01"), "M0 0L10.123 01");
+    }
+
+    #[test]
+    fn minified_arcs_draw_the_same_path() {
+        let text = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 20 20">
+            <path d="M2 2a.5.5 0 01.5.5l3 3a4 4 0 11-2 2A3.25 3.25 0 0010 10z"/>
+        </svg>"#;
+        let minified = minify_svg(text, &SvgSettings::default()).unwrap();
+
+        let bounds = |text: &str| {
+            let tree = usvg::Tree::from_str(text, &usvg::Options::default()).unwrap();
+            let rect = tree.root().bounding_box();
+            [rect.x(), rect.y(), rect.width(), rect.height()]
+        };
+        let (before, after) = (bounds(text), bounds(&minified));
+        for (a, b) in before.iter().zip(after) {
+            assert!((a - b).abs() < 1e-3, "{:?} != {:?}", before, after);
+        }
+    }
+}
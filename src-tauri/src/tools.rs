@@ -1,9 +1,14 @@
 use std::ffi::OsStr;
+#[cfg(target_os = "windows")]
 use std::fs;
+#[cfg(target_os = "windows")]
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 use tempfile::TempDir;
 
+use crate::types::ToolStatus;
+
 #[cfg(target_os = "windows")]
 const PNGQUANT_BIN: &[u8] = include_bytes!("../bin/pngquant.exe");
 #[cfg(target_os = "windows")]
@@ -44,3 +49,97 @@ pub fn get_png_tools() -> Result<(Option<TempDir>, ToolPath, ToolPath), std::io:
         ))
     }
 }
+
+pub fn get_avif_decoder() -> ToolPath {
+    ToolPath::Command("avifdec".to_string())
+}
+
+pub fn get_jxl_encoder() -> ToolPath {
+    ToolPath::Command("cjxl".to_string())
+}
+
+// Console tools would flash a window on every run from the GUI on Windows
+pub fn command<S: AsRef<OsStr>>(program: S) -> Command {
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+        let mut cmd = Command::new(program);
+        cmd.creation_flags(CREATE_NO_WINDOW);
+        cmd
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        Command::new(program)
+    }
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let file_name = if cfg!(target_os = "windows") {
+        format!("{}.exe", name)
+    } else {
+        name.to_string()
+    };
+
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(&file_name))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+pub fn probe_tool(name: &str, tool: &ToolPath) -> ToolStatus {
+    let path = match tool {
+        ToolPath::Path(p) => Some(p.clone()),
+        ToolPath::Command(c) => find_in_path(c),
+    };
+
+    let mut status = ToolStatus {
+        name: name.to_string(),
+        path: path.as_ref().map(|p| p.to_string_lossy().to_string()),
+        version: None,
+        available: false,
+        error: None,
+    };
+
+    let Some(path) = path else {
+        status.error = Some(format!("{} was not found in PATH", name));
+        return status;
+    };
+
+    let mut cmd = command(&path);
+    cmd.arg("--version");
+
+    match cmd.output() {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            status.version = stdout.lines().next().map(|l| l.trim().to_string());
+            status.available = true;
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            status.error = Some(format!(
+                "{} --version exited with {}: {}",
+                name,
+                output.status,
+                stderr.trim()
+            ));
+        }
+        Err(e) => status.error = Some(format!("Failed to run {}: {}", name, e)),
+    }
+
+    status
+}
+
+pub fn check_tools() -> Result<Vec<ToolStatus>, String> {
+    let (_tmp_dir, pq, oxi) =
+        get_png_tools().map_err(|e| format!("Failed to setup tools: {}", e))?;
+    Ok(vec![
+        probe_tool("pngquant", &pq),
+        probe_tool("oxipng", &oxi),
+        probe_tool("avifdec", &get_avif_decoder()),
+        probe_tool("cjxl", &get_jxl_encoder()),
+    ])
+}
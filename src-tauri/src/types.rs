@@ -18,30 +18,443 @@ pub struct AppState {
     pub last_result: Mutex<Option<FinalResult>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FileTask {
     pub path: String,
     pub root: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct OptimizeConfig {
     pub tasks: Vec<FileTask>,
     pub jpg_q: u8,
     pub png_min: u8,
     pub png_max: u8,
+    #[serde(default)]
+    pub png_backend: PngBackend,
+    #[serde(default = "default_tool_timeout")]
+    pub tool_timeout_secs: u64,
+    #[serde(default)]
+    pub png_preset: PngPreset,
+    #[serde(default)]
+    pub png_settings: Option<PngSettings>,
     pub webp: bool,
     pub avif: bool,
+    #[serde(default)]
+    pub jxl: bool,
     #[serde(default = "default_true")]
     pub optimize_original: bool,
     pub replace: bool,
+    #[serde(default)]
+    pub backup: bool,
+    #[serde(default)]
+    pub backup_dir: Option<String>,
+    #[serde(default = "default_backup_keep_runs")]
+    pub backup_keep_runs: usize,
     pub output_dir: Option<String>,
+    #[serde(default)]
+    pub convert_to: ConvertTarget,
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    #[serde(default)]
+    pub resize: Option<ResizeConfig>,
+    #[serde(default)]
+    pub responsive_widths: Vec<u32>,
+    #[serde(default)]
+    pub target_ssim: Option<f64>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub downscale_to_fit: bool,
+    #[serde(default)]
+    pub webp_settings: WebpSettings,
+    #[serde(default)]
+    pub avif_settings: AvifSettings,
+    #[serde(default)]
+    pub jxl_settings: JxlSettings,
+    #[serde(default)]
+    pub svg_settings: SvgSettings,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub dry_run_sample: Option<usize>,
+    #[serde(default)]
+    pub incremental: bool,
+    #[serde(default)]
+    pub manifest_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WebpSettings {
+    pub quality: u8,
+    pub mode: WebpMode,
+    pub near_lossless: u8,
+    pub method: u8,
+    pub alpha_quality: u8,
+}
+
+impl Default for WebpSettings {
+    fn default() -> Self {
+        WebpSettings {
+            quality: 75,
+            mode: WebpMode::Lossy,
+            near_lossless: 60,
+            method: 4,
+            alpha_quality: 100,
+        }
+    }
+}
+
+impl WebpSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.quality > 100 {
+            return Err(format!(
+                "WebP quality must be between 0 and 100, got {}",
+                self.quality
+            ));
+        }
+        if self.near_lossless > 100 {
+            return Err(format!(
+                "WebP near-lossless level must be between 0 and 100, got {}",
+                self.near_lossless
+            ));
+        }
+        if self.method > 6 {
+            return Err(format!(
+                "WebP method must be between 0 and 6, got {}",
+                self.method
+            ));
+        }
+        if self.alpha_quality > 100 {
+            return Err(format!(
+                "WebP alpha quality must be between 0 and 100, got {}",
+                self.alpha_quality
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebpMode {
+    #[default]
+    Lossy,
+    Lossless,
+    NearLossless,
+    Auto,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AvifSettings {
+    pub quality: u8,
+    pub speed: u8,
+    pub alpha_quality: u8,
+    pub bit_depth: AvifBitDepth,
+    pub chroma_subsampling: ChromaSubsampling,
+}
+
+impl Default for AvifSettings {
+    fn default() -> Self {
+        AvifSettings {
+            quality: 65,
+            speed: 4,
+            alpha_quality: 70,
+            bit_depth: AvifBitDepth::Auto,
+            chroma_subsampling: ChromaSubsampling::Yuv444,
+        }
+    }
+}
+
+impl AvifSettings {
+    // ravif asserts on these ranges, so reject them before they reach the encoder
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.quality) {
+            return Err(format!(
+                "AVIF quality must be between 1 and 100, got {}",
+                self.quality
+            ));
+        }
+        if !(1..=10).contains(&self.speed) {
+            return Err(format!(
+                "AVIF speed must be between 1 and 10, got {}",
+                self.speed
+            ));
+        }
+        if !(1..=100).contains(&self.alpha_quality) {
+            return Err(format!(
+                "AVIF alpha quality must be between 1 and 100, got {}",
+                self.alpha_quality
+            ));
+        }
+        if self.chroma_subsampling != ChromaSubsampling::Yuv444 {
+            return Err(
+                "AVIF chroma subsampling other than 4:4:4 is not supported by the encoder".into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AvifBitDepth {
+    Eight,
+    Ten,
+    #[default]
+    Auto,
+}
+
+// ravif only encodes 4:4:4, so 4:2:0 is rejected by validation instead of ignored
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChromaSubsampling {
+    #[default]
+    Yuv444,
+    Yuv420,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct JxlSettings {
+    pub mode: JxlMode,
+    pub quality: u8,
+    pub effort: u8,
+    pub lossless_jpeg: bool,
+}
+
+impl Default for JxlSettings {
+    fn default() -> Self {
+        JxlSettings {
+            mode: JxlMode::Lossy,
+            quality: 85,
+            effort: 7,
+            lossless_jpeg: true,
+        }
+    }
+}
+
+impl JxlSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.quality) {
+            return Err(format!(
+                "JPEG XL quality must be between 1 and 100, got {}",
+                self.quality
+            ));
+        }
+        if !(1..=9).contains(&self.effort) {
+            return Err(format!(
+                "JPEG XL effort must be between 1 and 9, got {}",
+                self.effort
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JxlMode {
+    #[default]
+    Lossy,
+    Lossless,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SvgSettings {
+    pub precision: u8,
+}
+
+impl Default for SvgSettings {
+    fn default() -> Self {
+        SvgSettings { precision: 3 }
+    }
+}
+
+impl SvgSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.precision > 8 {
+            return Err(format!(
+                "SVG precision must be between 0 and 8, got {}",
+                self.precision
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResizeConfig {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub fit: FitMode,
+    #[serde(default)]
+    pub filter: ResizeFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FitMode {
+    #[default]
+    Contain,
+    Cover,
+    Fill,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PngBackend {
+    #[default]
+    Native,
+    External,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PngPreset {
+    Fast,
+    #[default]
+    Balanced,
+    Maximum,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PngInterlace {
+    #[default]
+    Off,
+    Adam7,
+    Keep,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PngSettings {
+    pub oxipng_level: u8,
+    #[serde(default)]
+    pub zopfli: bool,
+    #[serde(default)]
+    pub interlace: PngInterlace,
+    pub speed: u8,
+    pub dithering: f32,
+    #[serde(default)]
+    pub posterize: u8,
+}
+
+impl From<PngPreset> for PngSettings {
+    fn from(preset: PngPreset) -> Self {
+        let (oxipng_level, zopfli, speed) = match preset {
+            PngPreset::Fast => (2, false, 8),
+            PngPreset::Balanced => (4, false, 3),
+            PngPreset::Maximum => (6, true, 1),
+        };
+        PngSettings {
+            oxipng_level,
+            zopfli,
+            interlace: PngInterlace::Off,
+            speed,
+            dithering: 1.0,
+            posterize: 0,
+        }
+    }
+}
+
+impl PngSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.oxipng_level > 6 {
+            return Err(format!(
+                "oxipng level must be between 0 and 6, got {}",
+                self.oxipng_level
+            ));
+        }
+        if !(1..=10).contains(&self.speed) {
+            return Err(format!(
+                "PNG quantization speed must be between 1 and 10, got {}",
+                self.speed
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.dithering) {
+            return Err(format!(
+                "PNG dithering must be between 0 and 1, got {}",
+                self.dithering
+            ));
+        }
+        if self.posterize > 4 {
+            return Err(format!(
+                "PNG posterize must be between 0 and 4 bits, got {}",
+                self.posterize
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    #[default]
+    StripAll,
+    KeepIcc,
+    KeepCopyright,
+    KeepAll,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConvertTarget {
+    Jpeg,
+    #[default]
+    Png,
+    Webp,
+    Avif,
+}
+
+impl ConvertTarget {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConvertTarget::Jpeg => "jpg",
+            ConvertTarget::Png => "png",
+            ConvertTarget::Webp => "webp",
+            ConvertTarget::Avif => "avif",
+        }
+    }
 }
 
 fn default_true() -> bool {
     true
 }
 
+fn default_tool_timeout() -> u64 {
+    300
+}
+
+fn default_backup_keep_runs() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolStatus {
+    pub name: String,
+    pub path: Option<String>,
+    pub version: Option<String>,
+    pub available: bool,
+    pub error: Option<String>,
+}
+
 #[derive(Clone, Serialize)]
 pub struct ProgressPayload {
     pub total: u64,
@@ -59,20 +472,100 @@ pub struct FinalResult {
     pub duration_opt: f64,
     pub duration_webp: f64,
     pub duration_avif: f64,
+    pub duration_jxl: f64,
     pub total_size_original: u64,
     pub total_size_optimized: u64,
     pub total_size_webp: u64,
     pub total_size_avif: u64,
+    pub total_size_jxl: u64,
+    pub total_size_variants: u64,
+    pub kept_original_files: u64,
+    pub discarded_webp: u64,
+    pub discarded_avif: u64,
+    pub discarded_jxl: u64,
+    pub skipped_files: u64,
+    pub failed_files: u64,
+    pub backup_run: Option<String>,
+    pub is_estimate: bool,
+    pub sampled_files: u64,
+    pub files: Vec<FileStats>,
 }
 
-#[derive(Default)]
+#[derive(Clone, Serialize)]
+pub struct RestoreReport {
+    pub run_id: String,
+    pub restored: Vec<String>,
+    pub failed: Vec<RestoreFailure>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RestoreFailure {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "snake_case")]
+pub enum FileOutcome {
+    Optimized,
+    #[default]
+    Unchanged,
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Clone, Default, Serialize)]
 pub struct FileStats {
+    pub path: String,
+    pub outcome: FileOutcome,
     pub bytes_saved: u64,
     pub original_size: u64,
     pub optimized_size: u64,
     pub webp_size: u64,
     pub avif_size: u64,
+    pub jxl_size: u64,
     pub duration_opt: f64,
     pub duration_webp: f64,
     pub duration_avif: f64,
+    pub duration_jxl: f64,
+    pub kept_original: bool,
+    pub webp_discarded: bool,
+    pub webp_mode: Option<WebpMode>,
+    pub avif_discarded: bool,
+    pub jxl_discarded: bool,
+    pub variants: Vec<OutputFile>,
+    pub qualities: Vec<QualityChoice>,
+    pub warnings: Vec<String>,
+    pub tool_log: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct QualityChoice {
+    pub format: String,
+    pub quality: u8,
+    pub ssim: Option<f64>,
+    pub downscaled_to: Option<(u32, u32)>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct OutputFile {
+    pub path: String,
+    pub width: u32,
+    pub size: u64,
+}
+
+impl FileStats {
+    pub fn skipped(reason: impl Into<String>) -> Self {
+        FileStats {
+            outcome: FileOutcome::Skipped(reason.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(reason: impl Into<String>) -> Self {
+        FileStats {
+            outcome: FileOutcome::Failed(reason.into()),
+            ..Default::default()
+        }
+    }
 }